@@ -0,0 +1,163 @@
+//! Crash-safe file replacement.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::Context;
+
+/// Replaces `path` with `contents` so that readers (and the file after a
+/// crash) only ever see the old or the new version, never a truncated one.
+///
+/// The data goes to a hidden sibling temp file which is fsynced, renamed over
+/// `path`, and then the directory is fsynced so the rename itself is durable.
+/// An existing file's permissions are carried over to the new one.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let dir = path
+        .parent()
+        .with_context(|| format!("{} has no parent directory", path.display()))?;
+    let tmp_path = temp_path(path)?;
+
+    let result = write_temp(path, &tmp_path, contents).and_then(|()| {
+        fs::rename(&tmp_path, path).with_context(|| {
+            format!("cannot rename {} to {}", tmp_path.display(), path.display())
+        })
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result?;
+
+    sync_dir(dir)
+}
+
+/// Creates `path` with `contents` only if it does not exist yet, returning
+/// `Ok(false)` (and leaving the existing file alone) if it does.
+///
+/// The data is written and fsynced to a temp file first and then hard-linked
+/// into place. `link(2)` fails with `EEXIST` like `open(O_CREAT | O_EXCL)`,
+/// but unlike writing through an `O_EXCL` descriptor, readers can never see
+/// the new file half-written.
+pub fn create_atomic(path: &Path, contents: &[u8]) -> anyhow::Result<bool> {
+    let dir = path
+        .parent()
+        .with_context(|| format!("{} has no parent directory", path.display()))?;
+    let tmp_path = temp_path(path)?;
+
+    let result = write_temp(path, &tmp_path, contents).and_then(|()| {
+        match fs::hard_link(&tmp_path, path) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(false),
+            Err(e) => Err(e).with_context(|| format!("cannot create {}", path.display())),
+        }
+    });
+    let _ = fs::remove_file(&tmp_path);
+    let created = result?;
+    if created {
+        sync_dir(dir)?;
+    }
+    Ok(created)
+}
+
+fn write_temp(path: &Path, tmp_path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(tmp_path)
+        .with_context(|| format!("cannot create {}", tmp_path.display()))?;
+    file.write_all(contents)
+        .with_context(|| format!("cannot write {}", tmp_path.display()))?;
+    if let Ok(metadata) = fs::metadata(path) {
+        file.set_permissions(metadata.permissions())
+            .with_context(|| format!("cannot set permissions on {}", tmp_path.display()))?;
+    }
+    file.sync_all()
+        .with_context(|| format!("cannot sync {}", tmp_path.display()))
+}
+
+/// Hidden, per-process-unique sibling of `path`, e.g. `.3.json.tmp.1234.0`.
+/// The leading dot and non-`.json` extension keep it out of task scans.
+fn temp_path(path: &Path) -> anyhow::Result<PathBuf> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .with_context(|| format!("{} has no file name", path.display()))?;
+    let seq = COUNTER.fetch_add(1, Ordering::Relaxed);
+    Ok(path.with_file_name(format!(".{name}.tmp.{}.{seq}", std::process::id())))
+}
+
+fn sync_dir(dir: &Path) -> anyhow::Result<()> {
+    fs::File::open(dir)
+        .and_then(|d| d.sync_all())
+        .with_context(|| format!("cannot sync directory {}", dir.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn replaces_contents_without_leftovers() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("1.json");
+        fs::write(&path, "old contents that are longer").unwrap();
+
+        write_atomic(&path, b"new").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        let entries: Vec<_> = fs::read_dir(tmp.path()).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+    }
+
+    #[test]
+    fn creates_missing_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("2.json");
+
+        write_atomic(&path, b"{}").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{}");
+    }
+
+    #[test]
+    fn preserves_permissions() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("3.json");
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+
+        write_atomic(&path, b"new").unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn create_refuses_existing_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("5.json");
+
+        assert!(create_atomic(&path, b"first").unwrap());
+        assert!(!create_atomic(&path, b"second").unwrap());
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "first");
+        let entries: Vec<_> = fs::read_dir(tmp.path()).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+    }
+
+    #[test]
+    fn failed_write_leaves_original_intact() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("4.json");
+        fs::create_dir(&path).unwrap();
+
+        assert!(write_atomic(&path, b"new").is_err());
+
+        assert!(path.is_dir());
+        let entries: Vec<_> = fs::read_dir(tmp.path()).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+    }
+}
@@ -0,0 +1,479 @@
+//! Consistency checks (and mechanical repairs) for a team directory.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::{TaskFile, ToolOutput, dependency_graph, dependency_path, id_order, read_task};
+
+/// How much an [`Issue`] matters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// Tasks are unreadable or can never be claimed as intended.
+    Error,
+    /// Tasks work, but tools that trust both sides of a dependency may not.
+    Warning,
+    /// Harmless noise.
+    Info,
+}
+
+/// What kind of inconsistency an [`Issue`] describes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum IssueKind {
+    /// The file is not valid JSON or not a task.
+    InvalidTask,
+    /// The `id` field differs from the file name.
+    IdMismatch,
+    /// `blockedBy` names a task that does not exist.
+    DanglingBlockedBy,
+    /// `blocks` names a task that does not exist.
+    DanglingBlocks,
+    /// A dependency is recorded in `blocks` or `blockedBy` but not both.
+    AsymmetricDependency,
+    /// The same ID appears twice in `blocks` or `blockedBy`.
+    DuplicateReference,
+    /// Tasks wait on each other, so none of them can ever be claimed.
+    Cycle,
+    /// A `pending` task has an owner, which makes it unclaimable.
+    OwnerOnPending,
+    /// An `in_progress` task has no owner, so nobody can release or
+    /// complete it.
+    UnownedInProgress,
+}
+
+/// A single finding of [`check`].
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Issue {
+    pub severity: Severity,
+    pub kind: IssueKind,
+    /// The task file the issue was found in (its file name without `.json`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub task_id: Option<String>,
+    pub message: String,
+    /// For `CYCLE`, the task IDs along the cycle, first and last equal.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub cycle: Vec<String>,
+    /// Whether `repair` can fix this mechanically.
+    pub repairable: bool,
+    /// Whether this run fixed it.
+    pub repaired: bool,
+    #[serde(skip)]
+    #[schemars(skip)]
+    fix: Option<Fix>,
+}
+
+impl Issue {
+    fn new(severity: Severity, kind: IssueKind, task_id: &str, message: String) -> Self {
+        Self {
+            severity,
+            kind,
+            task_id: Some(task_id.to_string()),
+            message,
+            cycle: Vec::new(),
+            repairable: false,
+            repaired: false,
+            fix: None,
+        }
+    }
+
+    fn repairable(mut self, fix: Fix) -> Self {
+        self.repairable = true;
+        self.fix = Some(fix);
+        self
+    }
+}
+
+/// A mechanical repair, applied to the task at the given index.
+#[derive(Debug, Clone)]
+enum Fix {
+    Dedup(usize),
+    RemoveBlockedBy(usize, String),
+    RemoveBlocks(usize, String),
+    AddBlockedBy(usize, String),
+    AddBlocks(usize, String),
+    ClearOwner(usize),
+}
+
+/// The result of `check_team`.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Report {
+    pub team: String,
+    pub issues: Vec<Issue>,
+    /// Tasks changed by repairs, still to be written by the caller.
+    #[serde(skip)]
+    #[schemars(skip)]
+    pub changed: Vec<(PathBuf, TaskFile)>,
+}
+
+impl Report {
+    /// Whether any `error` issue is left unrepaired.
+    pub fn has_errors(&self) -> bool {
+        self.issues
+            .iter()
+            .any(|issue| issue.severity == Severity::Error && !issue.repaired)
+    }
+}
+
+impl ToolOutput for Report {
+    /// A summary line followed by one line per issue.
+    fn message(&self) -> String {
+        let count = |severity| self.issues.iter().filter(|i| i.severity == severity).count();
+        let repaired = self.issues.iter().filter(|i| i.repaired).count();
+        let mut message = format!(
+            "Team {}: {} error(s), {} warning(s), {} info, {repaired} repaired",
+            self.team,
+            count(Severity::Error),
+            count(Severity::Warning),
+            count(Severity::Info),
+        );
+        for issue in &self.issues {
+            let severity = serde_json::to_value(issue.severity).unwrap_or_default();
+            let state = match (issue.repaired, issue.repairable) {
+                (true, _) => " (repaired)",
+                (false, true) => " (repairable)",
+                (false, false) => "",
+            };
+            let task = issue.task_id.as_deref().map(|id| format!("{id}: ")).unwrap_or_default();
+            let severity = severity.as_str().unwrap_or_default();
+            message.push_str(&format!("\n[{severity}] {task}{}{state}", issue.message));
+        }
+        message
+    }
+
+    fn structured(&self) -> anyhow::Result<serde_json::Value> {
+        Ok(serde_json::to_value(self)?)
+    }
+}
+
+/// A readable task file.
+struct Entry {
+    /// File name without `.json`; used as the task's ID even if `id` differs.
+    id: String,
+    path: PathBuf,
+    task: TaskFile,
+}
+
+/// Scans every `*.json` file in `team_dir` and reports inconsistencies. With
+/// `repair`, mechanical fixes are applied to the in-memory tasks and the
+/// changed tasks returned in [`Report::changed`]; the caller, holding the
+/// exclusive team lock, writes them.
+pub fn check(team_dir: &Path, team: &str, repair: bool) -> anyhow::Result<Report> {
+    let mut issues = Vec::new();
+    let mut known = HashSet::new();
+    let mut entries = Vec::new();
+    let dir = fs::read_dir(team_dir)
+        .with_context(|| format!("cannot read {}", team_dir.display()))?;
+    for entry in dir {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        let Some(id) = path.file_stem().and_then(|s| s.to_str()).map(str::to_string) else {
+            continue;
+        };
+        if id.starts_with('.') {
+            continue;
+        }
+        known.insert(id.clone());
+        match read_task(&path, &id) {
+            Ok(task) => entries.push(Entry { id, path, task }),
+            Err(e) => issues.push(Issue::new(
+                Severity::Error,
+                IssueKind::InvalidTask,
+                &id,
+                format!("{e:#}"),
+            )),
+        }
+    }
+    entries.sort_by_cached_key(|entry| id_order(&entry.id));
+    let index: HashMap<&str, usize> = entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| (entry.id.as_str(), i))
+        .collect();
+
+    for (i, entry) in entries.iter().enumerate() {
+        check_task(i, entry, &entries, &index, &known, &mut issues);
+    }
+    issues.extend(find_cycles(&entries));
+
+    let mut changed = Vec::new();
+    if repair {
+        let mut touched = HashSet::new();
+        for issue in issues.iter_mut() {
+            if let Some(fix) = &issue.fix {
+                touched.insert(apply(fix, &mut entries));
+                issue.repaired = true;
+            }
+        }
+        let mut touched: Vec<usize> = touched.into_iter().collect();
+        touched.sort_unstable();
+        changed = touched
+            .into_iter()
+            .map(|i| (entries[i].path.clone(), entries[i].task.clone()))
+            .collect();
+    }
+
+    Ok(Report {
+        team: team.to_string(),
+        issues,
+        changed,
+    })
+}
+
+/// Per-task checks: ID, duplicates, dangling and one-sided references, and
+/// owner/status combinations.
+fn check_task(
+    i: usize,
+    entry: &Entry,
+    entries: &[Entry],
+    index: &HashMap<&str, usize>,
+    known: &HashSet<String>,
+    issues: &mut Vec<Issue>,
+) {
+    let (id, task) = (entry.id.as_str(), &entry.task);
+    if task.id != id {
+        let message = format!("id field is {:?} but the file is {id}.json", task.id);
+        issues.push(Issue::new(Severity::Error, IssueKind::IdMismatch, id, message));
+    }
+
+    let has_duplicates = |list: &[String]| list.iter().collect::<HashSet<_>>().len() != list.len();
+    if has_duplicates(&task.blocks) || has_duplicates(&task.blocked_by) {
+        let message = "blocks or blockedBy lists an ID more than once".to_string();
+        issues.push(
+            Issue::new(Severity::Info, IssueKind::DuplicateReference, id, message)
+                .repairable(Fix::Dedup(i)),
+        );
+    }
+
+    let mut reported = HashSet::new();
+    for blocker in task.blocked_by.iter().filter(|b| reported.insert(("blockedBy", *b))) {
+        if !known.contains(blocker) {
+            let message = format!("blockedBy references missing task {blocker}");
+            issues.push(
+                Issue::new(Severity::Error, IssueKind::DanglingBlockedBy, id, message)
+                    .repairable(Fix::RemoveBlockedBy(i, blocker.clone())),
+            );
+        } else if let Some(&j) = index.get(blocker.as_str())
+            && !entries[j].task.blocks.iter().any(|b| b == id)
+        {
+            let message = format!("blockedBy {blocker}, but {blocker}'s blocks lacks {id}");
+            issues.push(
+                Issue::new(Severity::Warning, IssueKind::AsymmetricDependency, id, message)
+                    .repairable(Fix::AddBlocks(j, id.to_string())),
+            );
+        }
+    }
+    for blocked in task.blocks.iter().filter(|b| reported.insert(("blocks", *b))) {
+        if !known.contains(blocked) {
+            let message = format!("blocks references missing task {blocked}");
+            issues.push(
+                Issue::new(Severity::Warning, IssueKind::DanglingBlocks, id, message)
+                    .repairable(Fix::RemoveBlocks(i, blocked.clone())),
+            );
+        } else if let Some(&j) = index.get(blocked.as_str())
+            && !entries[j].task.blocked_by.iter().any(|b| b == id)
+        {
+            let message = format!("blocks {blocked}, but {blocked}'s blockedBy lacks {id}");
+            issues.push(
+                Issue::new(Severity::Warning, IssueKind::AsymmetricDependency, id, message)
+                    .repairable(Fix::AddBlockedBy(j, id.to_string())),
+            );
+        }
+    }
+
+    let owner = task.owner.as_deref().filter(|o| !o.is_empty());
+    match (task.status.as_str(), owner) {
+        ("pending", Some(owner)) => {
+            let message = format!("pending but owned by {owner}, so nobody can claim it");
+            issues.push(
+                Issue::new(Severity::Warning, IssueKind::OwnerOnPending, id, message)
+                    .repairable(Fix::ClearOwner(i)),
+            );
+        }
+        ("in_progress", None) => {
+            let message = "in_progress without an owner".to_string();
+            issues.push(Issue::new(
+                Severity::Warning,
+                IssueKind::UnownedInProgress,
+                id,
+                message,
+            ));
+        }
+        _ => {}
+    }
+}
+
+/// Reports each dependency cycle once, with the path around it.
+fn find_cycles(entries: &[Entry]) -> Vec<Issue> {
+    let graph = dependency_graph(entries.iter().map(|entry| &entry.task));
+    let mut seen: HashSet<&str> = HashSet::new();
+    let mut issues = Vec::new();
+    for entry in entries {
+        let id = entry.task.id.as_str();
+        if seen.contains(id) {
+            continue;
+        }
+        let mut deps: Vec<&str> = graph.get(id).into_iter().flatten().map(String::as_str).collect();
+        deps.sort_unstable();
+        let cycle = deps.into_iter().find_map(|dep| {
+            let path = dependency_path(&graph, dep, id)?;
+            Some(std::iter::once(id).chain(path).collect::<Vec<_>>())
+        });
+        if let Some(cycle) = cycle {
+            seen.extend(cycle.iter().copied());
+            let message = format!("dependency cycle {}", cycle.join(" -> "));
+            let mut issue = Issue::new(Severity::Error, IssueKind::Cycle, id, message);
+            issue.cycle = cycle.into_iter().map(str::to_string).collect();
+            issues.push(issue);
+        }
+    }
+    issues
+}
+
+/// Applies `fix` and returns the index of the task it changed.
+fn apply(fix: &Fix, entries: &mut [Entry]) -> usize {
+    let add = |list: &mut Vec<String>, id: &str| {
+        if !list.iter().any(|x| x == id) {
+            list.push(id.to_string());
+        }
+    };
+    match fix {
+        Fix::Dedup(i) => {
+            let task = &mut entries[*i].task;
+            for list in [&mut task.blocks, &mut task.blocked_by] {
+                let mut seen = HashSet::new();
+                list.retain(|id| seen.insert(id.clone()));
+            }
+            *i
+        }
+        Fix::RemoveBlockedBy(i, id) => {
+            entries[*i].task.blocked_by.retain(|x| x != id);
+            *i
+        }
+        Fix::RemoveBlocks(i, id) => {
+            entries[*i].task.blocks.retain(|x| x != id);
+            *i
+        }
+        Fix::AddBlockedBy(i, id) => {
+            add(&mut entries[*i].task.blocked_by, id);
+            *i
+        }
+        Fix::AddBlocks(i, id) => {
+            add(&mut entries[*i].task.blocks, id);
+            *i
+        }
+        Fix::ClearOwner(i) => {
+            let task = &mut entries[*i].task;
+            task.owner = None;
+            task.clear_lease(false);
+            *i
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(dir: &Path, id: &str, json: serde_json::Value) {
+        fs::write(dir.join(format!("{id}.json")), json.to_string()).unwrap();
+    }
+
+    fn task(id: &str, status: &str, blocks: &[&str], blocked_by: &[&str]) -> serde_json::Value {
+        serde_json::json!({
+            "id": id,
+            "subject": format!("Task {id}"),
+            "status": status,
+            "blocks": blocks,
+            "blockedBy": blocked_by,
+        })
+    }
+
+    fn kinds(report: &Report) -> Vec<(Option<&str>, IssueKind)> {
+        report
+            .issues
+            .iter()
+            .map(|issue| (issue.task_id.as_deref(), issue.kind))
+            .collect()
+    }
+
+    #[test]
+    fn clean_team_has_no_issues() {
+        let tmp = tempfile::tempdir().unwrap();
+        write(tmp.path(), "1", task("1", "completed", &["2"], &[]));
+        write(tmp.path(), "2", task("2", "pending", &[], &["1"]));
+
+        let report = check(tmp.path(), "team", false).unwrap();
+        assert!(report.issues.is_empty(), "{}", report.message());
+        assert!(!report.has_errors());
+    }
+
+    #[test]
+    fn reports_each_kind_of_damage() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::write(tmp.path().join("1.json"), "{ not json").unwrap();
+        write(tmp.path(), "2", task("3", "pending", &[], &[]));
+        write(tmp.path(), "4", task("4", "pending", &["5", "9"], &["8"]));
+        write(tmp.path(), "5", task("5", "in_progress", &[], &[]));
+        let mut owned = task("6", "pending", &[], &["6"]);
+        owned["owner"] = "agent-a".into();
+        write(tmp.path(), "6", owned);
+        write(tmp.path(), "7", task("7", "pending", &["4", "4"], &["4"]));
+
+        let report = check(tmp.path(), "team", false).unwrap();
+        assert_eq!(
+            kinds(&report),
+            [
+                (Some("1"), IssueKind::InvalidTask),
+                (Some("2"), IssueKind::IdMismatch),
+                (Some("4"), IssueKind::DanglingBlockedBy),
+                (Some("4"), IssueKind::AsymmetricDependency),
+                (Some("4"), IssueKind::DanglingBlocks),
+                (Some("5"), IssueKind::UnownedInProgress),
+                (Some("6"), IssueKind::AsymmetricDependency),
+                (Some("6"), IssueKind::OwnerOnPending),
+                (Some("7"), IssueKind::DuplicateReference),
+                (Some("7"), IssueKind::AsymmetricDependency),
+                (Some("7"), IssueKind::AsymmetricDependency),
+                (Some("4"), IssueKind::Cycle),
+                (Some("6"), IssueKind::Cycle),
+            ]
+        );
+        assert_eq!(report.issues[11].cycle, ["4", "7", "4"]);
+        assert!(report.has_errors());
+        assert!(report.changed.is_empty());
+    }
+
+    #[test]
+    fn repair_fixes_mechanical_issues() {
+        let tmp = tempfile::tempdir().unwrap();
+        write(tmp.path(), "1", task("1", "pending", &["2", "2"], &["9"]));
+        let mut owned = task("2", "pending", &[], &[]);
+        owned["owner"] = "agent-a".into();
+        owned["metadata"] = serde_json::json!({ "claimedAt": 1, "leaseExpiresAt": 2 });
+        write(tmp.path(), "2", owned);
+
+        let report = check(tmp.path(), "team", true).unwrap();
+        assert!(report.issues.iter().all(|issue| issue.repaired));
+        assert!(!report.has_errors());
+
+        let changed: HashMap<&str, &TaskFile> = report
+            .changed
+            .iter()
+            .map(|(_, task)| (task.id.as_str(), task))
+            .collect();
+        assert_eq!(changed["1"].blocks, ["2"]);
+        assert!(changed["1"].blocked_by.is_empty());
+        assert_eq!(changed["2"].blocked_by, ["1"]);
+        assert_eq!(changed["2"].owner, None);
+        assert_eq!(changed["2"].metadata, Some(serde_json::json!({})));
+    }
+}
@@ -0,0 +1,269 @@
+//! Server configuration from the command line, environment and config file.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, bail};
+use serde::Deserialize;
+
+use crate::lock::{DEFAULT_LOCK_TIMEOUT, LockScope};
+
+/// Environment variable naming the default team.
+pub const TEAM_ENV: &str = "SAFE_TASK_CLAIM_TEAM";
+/// Environment variable overriding the tasks root.
+pub const TASKS_DIR_ENV: &str = "SAFE_TASK_CLAIM_TASKS_DIR";
+/// Environment variable overriding the lock acquisition deadline.
+pub const LOCK_TIMEOUT_ENV: &str = "SAFE_TASK_CLAIM_LOCK_TIMEOUT_MS";
+/// Environment variable choosing team-wide or per-task locking.
+pub const LOCK_SCOPE_ENV: &str = "SAFE_TASK_CLAIM_LOCK_SCOPE";
+
+/// Command-line options. These take precedence over the environment.
+#[derive(Debug, Default)]
+pub struct Args {
+    pub config: Option<PathBuf>,
+    pub tasks_dir: Option<PathBuf>,
+    pub team: Option<String>,
+    pub lock_timeout_ms: Option<u64>,
+    pub lock_scope: Option<LockScope>,
+    /// Run the `check` subcommand instead of serving MCP.
+    pub check: bool,
+    /// `check --repair`: fix mechanical issues.
+    pub repair: bool,
+}
+
+impl Args {
+    /// Parses `--config`, `--tasks-dir`, `--team`, `--lock-timeout-ms` and
+    /// `--lock-scope`, each given either as `--flag value` or `--flag=value`,
+    /// plus the `check` subcommand and its `--repair` switch.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> anyhow::Result<Self> {
+        let mut parsed = Self::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+                None => (arg, None),
+            };
+            let mut value = || match inline.clone().or_else(|| args.next()) {
+                Some(value) => Ok(value),
+                None => bail!("{flag} requires a value"),
+            };
+            match flag.as_str() {
+                "check" if inline.is_none() => parsed.check = true,
+                "--repair" if inline.is_none() => parsed.repair = true,
+                "--config" => parsed.config = Some(value()?.into()),
+                "--tasks-dir" => parsed.tasks_dir = Some(value()?.into()),
+                "--team" => parsed.team = Some(value()?),
+                "--lock-timeout-ms" => parsed.lock_timeout_ms = Some(parse_millis(&value()?)?),
+                "--lock-scope" => parsed.lock_scope = Some(value()?.parse()?),
+                _ => bail!("unknown argument: {flag}"),
+            }
+        }
+        if parsed.repair && !parsed.check {
+            bail!("--repair is only valid with check");
+        }
+        Ok(parsed)
+    }
+}
+
+/// Settings that apply when a tool call does not override them.
+///
+/// Read from `~/.config/safe-task-claim/config.json` (or `--config`), then
+/// overridden by environment variables, then by command-line flags.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct Config {
+    /// Root directory holding one subdirectory per team.
+    #[serde(default)]
+    pub tasks_dir: Option<PathBuf>,
+    /// Team used when a tool call does not name one.
+    #[serde(default)]
+    pub team: Option<String>,
+    /// How long to wait for a team lock before failing with `LOCK_TIMEOUT`.
+    #[serde(default)]
+    pub lock_timeout_ms: Option<u64>,
+    /// Whether single-task operations lock the whole team or just the task.
+    #[serde(default)]
+    pub lock_scope: Option<LockScope>,
+}
+
+impl Config {
+    /// Loads the config file (if any) and applies environment and
+    /// command-line overrides.
+    pub fn load(args: &Args) -> anyhow::Result<Self> {
+        let mut config = match args.config.clone().or_else(default_path) {
+            Some(path) => Self::read(&path)?,
+            None => Self::default(),
+        };
+        config.apply_env(|name| std::env::var(name).ok())?;
+        config.apply_args(args);
+        Ok(config)
+    }
+
+    /// The configured tasks root, or `~/.claude/tasks`.
+    pub fn tasks_dir(&self) -> anyhow::Result<PathBuf> {
+        if let Some(dir) = &self.tasks_dir {
+            return Ok(dir.clone());
+        }
+        match dirs::home_dir() {
+            Some(home) => Ok(home.join(".claude/tasks")),
+            None => bail!(
+                "cannot determine home directory; pass --tasks-dir or set {TASKS_DIR_ENV}"
+            ),
+        }
+    }
+
+    pub fn lock_timeout(&self) -> Duration {
+        self.lock_timeout_ms
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_LOCK_TIMEOUT)
+    }
+
+    pub fn lock_scope(&self) -> LockScope {
+        self.lock_scope.unwrap_or_default()
+    }
+
+    /// Reads a config file. A missing file yields the default config.
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e).with_context(|| format!("cannot read {}", path.display())),
+        };
+        serde_json::from_str(&content).with_context(|| format!("invalid config {}", path.display()))
+    }
+
+    fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> anyhow::Result<()> {
+        if let Some(dir) = var(TASKS_DIR_ENV).filter(|d| !d.is_empty()) {
+            self.tasks_dir = Some(dir.into());
+        }
+        if let Some(team) = var(TEAM_ENV).filter(|t| !t.is_empty()) {
+            self.team = Some(team);
+        }
+        if let Some(ms) = var(LOCK_TIMEOUT_ENV).filter(|m| !m.is_empty()) {
+            self.lock_timeout_ms =
+                Some(parse_millis(&ms).with_context(|| format!("invalid {LOCK_TIMEOUT_ENV}"))?);
+        }
+        if let Some(scope) = var(LOCK_SCOPE_ENV).filter(|s| !s.is_empty()) {
+            self.lock_scope =
+                Some(scope.parse().with_context(|| format!("invalid {LOCK_SCOPE_ENV}"))?);
+        }
+        Ok(())
+    }
+
+    fn apply_args(&mut self, args: &Args) {
+        if let Some(dir) = &args.tasks_dir {
+            self.tasks_dir = Some(dir.clone());
+        }
+        if let Some(team) = &args.team {
+            self.team = Some(team.clone());
+        }
+        if let Some(ms) = args.lock_timeout_ms {
+            self.lock_timeout_ms = Some(ms);
+        }
+        if let Some(scope) = args.lock_scope {
+            self.lock_scope = Some(scope);
+        }
+    }
+}
+
+fn parse_millis(value: &str) -> anyhow::Result<u64> {
+    value
+        .parse()
+        .with_context(|| format!("expected milliseconds, got {value:?}"))
+}
+
+fn default_path() -> Option<PathBuf> {
+    Some(dirs::config_dir()?.join("safe-task-claim/config.json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_settings_from_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("config.json");
+        let json = r#"{"team": "from-file", "tasks_dir": "/srv/tasks", "lock_scope": "task"}"#;
+        fs::write(&path, json).unwrap();
+
+        let config = Config::read(&path).unwrap();
+        assert_eq!(config.team.as_deref(), Some("from-file"));
+        assert_eq!(config.lock_scope(), LockScope::Task);
+        assert_eq!(config.tasks_dir().unwrap(), Path::new("/srv/tasks"));
+    }
+
+    #[test]
+    fn missing_file_is_default() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config = Config::read(&tmp.path().join("config.json")).unwrap();
+        assert_eq!(config.team, None);
+        assert_eq!(config.lock_timeout(), DEFAULT_LOCK_TIMEOUT);
+        assert_eq!(config.lock_scope(), LockScope::Team);
+    }
+
+    #[test]
+    fn env_overrides_file() {
+        let mut config = Config {
+            tasks_dir: Some("/from/file".into()),
+            team: Some("from-file".to_string()),
+            lock_timeout_ms: Some(1),
+            lock_scope: None,
+        };
+        config
+            .apply_env(|name| match name {
+                TEAM_ENV => Some("from-env".to_string()),
+                TASKS_DIR_ENV => Some("/from/env".to_string()),
+                LOCK_TIMEOUT_ENV => Some("250".to_string()),
+                LOCK_SCOPE_ENV => Some("task".to_string()),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(config.team.as_deref(), Some("from-env"));
+        assert_eq!(config.tasks_dir.as_deref(), Some(Path::new("/from/env")));
+        assert_eq!(config.lock_timeout(), Duration::from_millis(250));
+        assert_eq!(config.lock_scope(), LockScope::Task);
+
+        config.apply_env(|_| Some(String::new())).unwrap();
+        assert_eq!(config.team.as_deref(), Some("from-env"));
+
+        assert!(config.apply_env(|_| Some("soon".to_string())).is_err());
+    }
+
+    #[test]
+    fn args_override_env() {
+        let args = Args::parse(["--tasks-dir=/from/args".to_string()]).unwrap();
+        let mut config = Config::default();
+        config
+            .apply_env(|name| (name == TASKS_DIR_ENV).then(|| "/from/env".to_string()))
+            .unwrap();
+        config.apply_args(&args);
+        assert_eq!(config.tasks_dir().unwrap(), Path::new("/from/args"));
+    }
+
+    #[test]
+    fn parses_flags() {
+        let args = ["--tasks-dir", "/tmp/tasks", "--team=alpha", "--config", "c.json"];
+        let args = Args::parse(args.map(String::from)).unwrap();
+        assert_eq!(args.tasks_dir.as_deref(), Some(Path::new("/tmp/tasks")));
+        assert_eq!(args.team.as_deref(), Some("alpha"));
+        assert_eq!(args.config.as_deref(), Some(Path::new("c.json")));
+
+        let args = Args::parse(["--lock-timeout-ms=500".to_string()]).unwrap();
+        assert_eq!(args.lock_timeout_ms, Some(500));
+
+        let args = Args::parse(["--lock-scope".to_string(), "task".to_string()]).unwrap();
+        assert_eq!(args.lock_scope, Some(LockScope::Task));
+
+        assert!(Args::parse(["--tasks-dir".to_string()]).is_err());
+        assert!(Args::parse(["--lock-timeout-ms=soon".to_string()]).is_err());
+        assert!(Args::parse(["--lock-scope=row".to_string()]).is_err());
+        assert!(Args::parse(["--bogus".to_string()]).is_err());
+
+        let args = Args::parse(["check", "--team", "alpha", "--repair"].map(String::from)).unwrap();
+        assert!(args.check && args.repair);
+        assert_eq!(args.team.as_deref(), Some("alpha"));
+        assert!(!Args::parse(["--team=alpha".to_string()]).unwrap().check);
+        assert!(Args::parse(["--repair".to_string()]).is_err());
+    }
+}
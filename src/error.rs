@@ -0,0 +1,162 @@
+//! Machine-readable tool errors.
+
+use std::fmt;
+
+use serde::Serialize;
+
+/// Stable error codes returned in a failed tool call's structured content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    /// The task is owned by another agent or already in progress.
+    AlreadyClaimed,
+    /// The caller must own the task but nobody does.
+    NotClaimed,
+    /// The task is owned by a different agent.
+    NotOwner,
+    /// The task's status does not allow the operation.
+    InvalidState,
+    /// Some `blockedBy` tasks are not completed.
+    Blocked,
+    /// The team directory or task file does not exist.
+    NotFound,
+    /// `claim_next` found nothing eligible.
+    NoTaskAvailable,
+    /// No team was given and several exist.
+    AmbiguousTeam,
+    /// A parameter was rejected, e.g. a path-traversal attempt.
+    InvalidArgument,
+    /// The dependency would make tasks wait on each other; carries the cycle.
+    DependencyCycle,
+    /// The task changed since the caller read it; carries the current task.
+    Conflict,
+    /// The team lock is held and the caller asked not to wait.
+    LockBusy,
+    /// The team lock could not be acquired before the deadline.
+    LockTimeout,
+    /// The client cancelled the request; the task was not modified.
+    Cancelled,
+    /// Anything else, typically an IO failure.
+    Internal,
+}
+
+/// An error carrying an [`ErrorCode`] and the task state that caused it.
+///
+/// Raised through `anyhow` like any other error; tool handlers look for it
+/// in the error chain to build the structured response.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ToolError {
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    /// Code-specific extra data, e.g. the unfinished blockers for `BLOCKED`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+}
+
+impl ToolError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            owner: None,
+            status: None,
+            details: None,
+        }
+    }
+
+    /// Attaches the task's current owner and status.
+    pub fn with_state(mut self, owner: Option<&str>, status: &str) -> Self {
+        self.owner = owner.filter(|o| !o.is_empty()).map(str::to_string);
+        self.status = Some(status.to_string());
+        self
+    }
+
+    pub fn with_details(mut self, details: impl Serialize) -> Self {
+        self.details = serde_json::to_value(details).ok();
+        self
+    }
+
+    /// Finds the `ToolError` in `err`'s chain, or wraps `err` as `INTERNAL`.
+    pub fn from_anyhow(err: &anyhow::Error) -> Self {
+        match err.chain().find_map(|e| e.downcast_ref::<Self>()) {
+            Some(tool_error) => Self {
+                message: format!("{err:#}"),
+                ..tool_error.clone()
+            },
+            None => Self::new(ErrorCode::Internal, format!("{err:#}")),
+        }
+    }
+}
+
+impl fmt::Display for ToolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ToolError {}
+
+/// Returns early with a [`ToolError`], formatting the message like `bail!`.
+macro_rules! fail {
+    ($code:ident, $($arg:tt)*) => {
+        return Err($crate::error::ToolError::new(
+            $crate::error::ErrorCode::$code,
+            format!($($arg)*),
+        )
+        .into())
+    };
+}
+
+pub(crate) use fail;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn serializes_code_and_state() {
+        let err = ToolError::new(ErrorCode::AlreadyClaimed, "already claimed by agent-b")
+            .with_state(Some("agent-b"), "in_progress");
+        let json = serde_json::to_value(&err).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "code": "ALREADY_CLAIMED",
+                "message": "already claimed by agent-b",
+                "owner": "agent-b",
+                "status": "in_progress",
+            })
+        );
+    }
+
+    #[test]
+    fn from_anyhow_finds_wrapped_error() {
+        let err: anyhow::Error = ToolError::new(ErrorCode::NotFound, "task file not found").into();
+        let err = err.context("claiming task 3");
+
+        let found = ToolError::from_anyhow(&err);
+        assert_eq!(found.code, ErrorCode::NotFound);
+        assert_eq!(found.message, "claiming task 3: task file not found");
+    }
+
+    #[test]
+    fn from_anyhow_defaults_to_internal() {
+        let err = anyhow::anyhow!("disk on fire");
+        assert_eq!(ToolError::from_anyhow(&err).code, ErrorCode::Internal);
+    }
+
+    #[test]
+    fn fail_returns_tool_error() {
+        fn check(owner: &str) -> anyhow::Result<()> {
+            fail!(NotOwner, "claimed by {owner}");
+        }
+        let err = check("agent-b").context("ctx").unwrap_err();
+        assert_eq!(ToolError::from_anyhow(&err).code, ErrorCode::NotOwner);
+    }
+}
@@ -0,0 +1,127 @@
+//! Validated path components for team names and task IDs.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::error::fail;
+
+/// A team name or task ID that is safe to use as a single path component
+/// under the tasks root.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Ident(String);
+
+impl Ident {
+    /// Validates `value` as an identifier. `kind` names the parameter in
+    /// error messages (e.g. `"task_id"`).
+    ///
+    /// Rejects empty values, path separators, NUL bytes, and anything
+    /// starting with `.` (which covers `.`, `..` and hidden files such as
+    /// `.lock`).
+    pub fn parse(kind: &str, value: &str) -> anyhow::Result<Self> {
+        if value.is_empty() {
+            fail!(InvalidArgument, "invalid {kind}: must not be empty");
+        }
+        if value.starts_with('.') {
+            fail!(InvalidArgument, "invalid {kind} {value:?}: must not start with '.'");
+        }
+        if value.contains(['/', '\\', '\0']) {
+            fail!(
+                InvalidArgument,
+                "invalid {kind} {value:?}: must not contain path separators or NUL"
+            );
+        }
+        if Path::new(value).is_absolute() {
+            fail!(InvalidArgument, "invalid {kind} {value:?}: must not be an absolute path");
+        }
+        Ok(Self(value.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Ident {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Joins `name` onto `root` and, if the result exists, canonicalizes it and
+/// confirms it still lies under the canonical `root`. This catches symlinks
+/// that point outside the tasks root. Paths that do not exist are returned
+/// as joined so callers can report them as missing.
+pub fn resolve_under(root: &Path, name: &str) -> anyhow::Result<PathBuf> {
+    let path = root.join(name);
+    if path.symlink_metadata().is_err() {
+        return Ok(path);
+    }
+
+    let root = root
+        .canonicalize()
+        .with_context(|| format!("cannot resolve {}", root.display()))?;
+    let resolved = path
+        .canonicalize()
+        .with_context(|| format!("cannot resolve {}", path.display()))?;
+    if !resolved.starts_with(&root) {
+        fail!(InvalidArgument, "{} resolves outside {}", path.display(), root.display());
+    }
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_plain_names() {
+        for value in ["1", "42", "my-team", "team_2", "a.b"] {
+            assert_eq!(Ident::parse("team", value).unwrap().as_str(), value);
+        }
+    }
+
+    #[test]
+    fn rejects_traversal_attempts() {
+        for value in [
+            "",
+            ".",
+            "..",
+            ".lock",
+            "../../other-team/3",
+            "../3",
+            "a/b",
+            "a\\b",
+            "/etc/passwd",
+            "/",
+            "3\0",
+            "..\\..\\windows",
+        ] {
+            assert!(Ident::parse("task_id", value).is_err(), "{value:?} accepted");
+        }
+    }
+
+    #[test]
+    fn resolve_under_allows_children() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir(tmp.path().join("team")).unwrap();
+
+        let resolved = resolve_under(tmp.path(), "team").unwrap();
+        assert_eq!(resolved, tmp.path().canonicalize().unwrap().join("team"));
+        assert_eq!(resolve_under(tmp.path(), "missing").unwrap(), tmp.path().join("missing"));
+    }
+
+    #[test]
+    fn resolve_under_rejects_symlink_escape() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("tasks");
+        let outside = tmp.path().join("outside");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::create_dir(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("evil")).unwrap();
+
+        let err = resolve_under(&root, "evil").unwrap_err();
+        assert!(err.to_string().contains("resolves outside"));
+    }
+}
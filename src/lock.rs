@@ -0,0 +1,364 @@
+//! `flock`-based team locking with bounded waits.
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, bail};
+use serde::Deserialize;
+use tokio_util::sync::CancellationToken;
+
+use crate::error::{ErrorCode, ToolError};
+
+/// How long to wait for the team lock when no deadline is configured.
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+const MIN_BACKOFF: Duration = Duration::from_millis(1);
+const MAX_BACKOFF: Duration = Duration::from_millis(50);
+
+/// How to behave when the lock is held by someone else.
+#[derive(Debug, Clone)]
+pub struct LockOptions {
+    /// `false` fails immediately with `LOCK_BUSY` instead of waiting.
+    pub wait: bool,
+    /// Give up with `LOCK_TIMEOUT` after this long.
+    pub timeout: Duration,
+    /// Abandon the wait with `CANCELLED` once this is cancelled, e.g. by the
+    /// client's `notifications/cancelled`.
+    pub cancel: CancellationToken,
+}
+
+impl Default for LockOptions {
+    fn default() -> Self {
+        Self {
+            wait: true,
+            timeout: DEFAULT_LOCK_TIMEOUT,
+            cancel: CancellationToken::new(),
+        }
+    }
+}
+
+/// What a single-task operation (claim, release, complete, heartbeat,
+/// update) locks.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LockScope {
+    /// The team's `.lock`, exclusively: every mutation in the team serializes.
+    #[default]
+    Team,
+    /// The team's `.lock` shared, then `.locks/{id}.lock` for each task the
+    /// operation touches, so operations on different tasks run in parallel.
+    Task,
+}
+
+impl FromStr for LockScope {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<Self> {
+        match value {
+            "team" => Ok(Self::Team),
+            "task" => Ok(Self::Task),
+            _ => bail!("expected \"team\" or \"task\", got {value:?}"),
+        }
+    }
+}
+
+/// Which `flock` operation a [`TeamLock`] takes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// `LOCK_SH`: held alongside other readers, excludes writers.
+    Shared,
+    /// `LOCK_EX`: excludes everyone else.
+    Exclusive,
+}
+
+impl LockMode {
+    fn operation(self) -> libc::c_int {
+        match self {
+            Self::Shared => libc::LOCK_SH,
+            Self::Exclusive => libc::LOCK_EX,
+        }
+    }
+}
+
+/// A held `flock` on a lock file. The lock is released when the guard is
+/// dropped, so early returns and panics cannot leave it held.
+#[derive(Debug)]
+pub struct TeamLock {
+    file: fs::File,
+    mode: LockMode,
+}
+
+impl TeamLock {
+    /// Opens (creating if needed) the lock file at `path` and locks it in
+    /// `mode`, polling with `LOCK_NB` and exponential backoff until
+    /// `opts.timeout`.
+    ///
+    /// An exclusive holder writes its PID into the lock file so that waiters
+    /// can report who holds it. Cancellation is checked between polls and
+    /// once more after acquiring, so a cancelled caller never gets the lock.
+    pub fn acquire(path: &Path, mode: LockMode, opts: &LockOptions) -> anyhow::Result<Self> {
+        let file = open_lock_file(path)?;
+        let deadline = Instant::now() + opts.timeout;
+        let mut backoff = MIN_BACKOFF;
+        while !try_flock(&file, mode.operation())? {
+            let now = Instant::now();
+            if opts.cancel.is_cancelled() {
+                return Err(cancelled_error(path));
+            }
+            if !opts.wait {
+                return Err(busy_error(ErrorCode::LockBusy, "lock is busy", path));
+            }
+            if now >= deadline {
+                let waited = opts.timeout.as_millis();
+                let message = format!("timed out after {waited}ms waiting for lock");
+                return Err(busy_error(ErrorCode::LockTimeout, &message, path));
+            }
+            thread::sleep(backoff.min(deadline - now));
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+
+        let lock = Self { file, mode };
+        if opts.cancel.is_cancelled() {
+            return Err(cancelled_error(path));
+        }
+        if mode == LockMode::Exclusive {
+            let pid = std::process::id().to_string();
+            lock.file
+                .set_len(0)
+                .and_then(|()| lock.file.write_all_at(pid.as_bytes(), 0))
+                .with_context(|| format!("cannot write lock: {}", path.display()))?;
+        }
+        Ok(lock)
+    }
+}
+
+impl Drop for TeamLock {
+    /// Clears the recorded PID (readers never wrote one) and releases the
+    /// lock.
+    fn drop(&mut self) {
+        if self.mode == LockMode::Exclusive {
+            let _ = self.file.set_len(0);
+        }
+        unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN) };
+    }
+}
+
+/// Opens (creating if needed) the lock file at `path`.
+fn open_lock_file(path: &Path) -> anyhow::Result<fs::File> {
+    fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("cannot open lock: {}", path.display()))
+}
+
+/// Attempts a non-blocking `flock`; `Ok(false)` means someone else holds it.
+fn try_flock(file: &fs::File, operation: libc::c_int) -> anyhow::Result<bool> {
+    let ret = unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) };
+    if ret == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    if err.kind() == io::ErrorKind::WouldBlock {
+        return Ok(false);
+    }
+    bail!("flock failed: {err}");
+}
+
+/// PID recorded in the lock file by the current holder, if any. A holder
+/// that crashed leaves its PID behind, so the PID only counts while that
+/// process is still alive.
+fn holder_pid(path: &Path) -> Option<u32> {
+    let pid: u32 = fs::read_to_string(path).ok()?.trim().parse().ok()?;
+    process_alive(pid).then_some(pid)
+}
+
+/// Whether `pid` names a running process (possibly one we may not signal).
+fn process_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    if pid <= 0 {
+        return false;
+    }
+    let ret = unsafe { libc::kill(pid, 0) };
+    ret == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+fn busy_error(code: ErrorCode, message: &str, path: &Path) -> anyhow::Error {
+    let pid = holder_pid(path);
+    let message = match pid {
+        Some(pid) => format!("{message}: {} (held by pid {pid})", path.display()),
+        None => format!("{message}: {}", path.display()),
+    };
+    let mut error = ToolError::new(code, message);
+    if let Some(pid) = pid {
+        error = error.with_details(serde_json::json!({ "holderPid": pid }));
+    }
+    error.into()
+}
+
+fn cancelled_error(path: &Path) -> anyhow::Error {
+    let message = format!("request cancelled while waiting for lock: {}", path.display());
+    ToolError::new(ErrorCode::Cancelled, message).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_wait() -> LockOptions {
+        LockOptions {
+            wait: false,
+            ..Default::default()
+        }
+    }
+
+    fn exclusive(path: &Path) -> TeamLock {
+        TeamLock::acquire(path, LockMode::Exclusive, &LockOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn busy_lock_fails_fast_with_holder_pid() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(".lock");
+        let holder = exclusive(&path);
+
+        let err = TeamLock::acquire(&path, LockMode::Exclusive, &no_wait()).unwrap_err();
+        let error = ToolError::from_anyhow(&err);
+        assert_eq!(error.code, ErrorCode::LockBusy);
+        assert_eq!(error.details.unwrap()["holderPid"], std::process::id());
+
+        drop(holder);
+        TeamLock::acquire(&path, LockMode::Exclusive, &no_wait()).unwrap();
+    }
+
+    #[test]
+    fn held_lock_times_out() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(".lock");
+        let _holder = exclusive(&path);
+
+        let opts = LockOptions {
+            timeout: Duration::from_millis(30),
+            ..Default::default()
+        };
+        let started = Instant::now();
+        let err = TeamLock::acquire(&path, LockMode::Exclusive, &opts).unwrap_err();
+        assert!(started.elapsed() >= Duration::from_millis(30));
+        assert_eq!(ToolError::from_anyhow(&err).code, ErrorCode::LockTimeout);
+    }
+
+    #[test]
+    fn waiter_acquires_once_released() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(".lock");
+        let holder = exclusive(&path);
+
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            drop(holder);
+        });
+        exclusive(&path);
+        releaser.join().unwrap();
+    }
+
+    #[test]
+    fn cancellation_abandons_wait() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(".lock");
+        let _holder = exclusive(&path);
+
+        let opts = LockOptions::default();
+        let cancel = opts.cancel.clone();
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            cancel.cancel();
+        });
+        let started = Instant::now();
+        let err = TeamLock::acquire(&path, LockMode::Exclusive, &opts).unwrap_err();
+        canceller.join().unwrap();
+        assert!(started.elapsed() < DEFAULT_LOCK_TIMEOUT);
+        assert_eq!(ToolError::from_anyhow(&err).code, ErrorCode::Cancelled);
+    }
+
+    #[test]
+    fn cancelled_caller_does_not_keep_free_lock() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(".lock");
+        let opts = LockOptions::default();
+        opts.cancel.cancel();
+
+        let err = TeamLock::acquire(&path, LockMode::Exclusive, &opts).unwrap_err();
+        assert_eq!(ToolError::from_anyhow(&err).code, ErrorCode::Cancelled);
+
+        TeamLock::acquire(&path, LockMode::Exclusive, &no_wait()).unwrap();
+    }
+
+    #[test]
+    fn drop_clears_recorded_pid() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(".lock");
+        let lock = exclusive(&path);
+        assert_eq!(holder_pid(&path), Some(std::process::id()));
+
+        drop(lock);
+        assert_eq!(holder_pid(&path), None);
+    }
+
+    #[test]
+    fn stale_pid_of_crashed_holder_is_not_reported() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(".lock");
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let dead_pid = child.id();
+        child.wait().unwrap();
+        fs::write(&path, dead_pid.to_string()).unwrap();
+
+        let _reader = TeamLock::acquire(&path, LockMode::Shared, &no_wait()).unwrap();
+        let err = TeamLock::acquire(&path, LockMode::Exclusive, &no_wait()).unwrap_err();
+        let error = ToolError::from_anyhow(&err);
+        assert_eq!(error.code, ErrorCode::LockBusy);
+        assert!(error.details.is_none());
+        assert!(!error.message.contains("held by pid"));
+    }
+
+    #[test]
+    fn panic_releases_lock() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(".lock");
+
+        let result = std::panic::catch_unwind(|| {
+            let _lock = exclusive(&path);
+            panic!("operation failed while holding the lock");
+        });
+        assert!(result.is_err());
+        TeamLock::acquire(&path, LockMode::Exclusive, &no_wait()).unwrap();
+    }
+
+    #[test]
+    fn shared_locks_exclude_only_writers() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(".lock");
+        let reader = TeamLock::acquire(&path, LockMode::Shared, &no_wait()).unwrap();
+        let other = TeamLock::acquire(&path, LockMode::Shared, &no_wait()).unwrap();
+
+        let err = TeamLock::acquire(&path, LockMode::Exclusive, &no_wait()).unwrap_err();
+        assert_eq!(ToolError::from_anyhow(&err).code, ErrorCode::LockBusy);
+
+        drop((reader, other));
+        let _writer = exclusive(&path);
+        let err = TeamLock::acquire(&path, LockMode::Shared, &no_wait()).unwrap_err();
+        let error = ToolError::from_anyhow(&err);
+        assert_eq!(error.code, ErrorCode::LockBusy);
+        assert_eq!(error.details.unwrap()["holderPid"], std::process::id());
+    }
+}
@@ -1,19 +1,39 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fs;
-use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 use anyhow::{Context, bail};
+use atomic::{create_atomic, write_atomic};
+use config::{Args, Config, TEAM_ENV};
+use error::{ErrorCode, ToolError, fail};
+use ident::{Ident, resolve_under};
+use lock::{LockMode, LockOptions, LockScope, TeamLock};
 use rmcp::{
-    ServerHandler, ServiceExt,
-    handler::server::{router::tool::ToolRouter, wrapper::Parameters},
-    model::{ServerCapabilities, ServerInfo},
+    ErrorData as McpError, Peer, RoleServer, ServerHandler, ServiceExt,
+    handler::server::{
+        common::cached_schema_for_type, router::tool::ToolRouter, wrapper::Parameters,
+    },
+    model::{
+        CallToolResult, Content, LoggingLevel, LoggingMessageNotificationParam,
+        ServerCapabilities, ServerInfo, SetLevelRequestParam,
+    },
+    service::RequestContext,
     tool, tool_handler, tool_router,
     transport::stdio,
 };
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
 
-#[derive(Debug, Deserialize, Serialize)]
+mod atomic;
+mod check;
+mod config;
+mod error;
+mod ident;
+mod lock;
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
 struct TaskFile {
     id: String,
     subject: String,
@@ -30,6 +50,216 @@ struct TaskFile {
     blocked_by: Vec<String>,
     #[serde(default)]
     metadata: Option<serde_json::Value>,
+    /// Fields this server does not know about, kept so that re-serializing a
+    /// task never drops data written by Claude Code or other tooling.
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
+    /// Top-level key order of the file this task was read from.
+    #[serde(skip)]
+    key_order: Vec<String>,
+}
+
+/// A successful task operation: a summary for humans and the task as written.
+#[derive(Debug)]
+struct TaskOutcome {
+    message: String,
+    task: TaskFile,
+    /// Set by `safe_complete` when finishing the task made others claimable.
+    unblocked: Option<Unblocked>,
+}
+
+impl TaskOutcome {
+    fn new(message: String, task: TaskFile) -> Self {
+        Self {
+            message,
+            task,
+            unblocked: None,
+        }
+    }
+}
+
+/// Tasks that became available because `completed` was completed; sent to
+/// the client as a logging notification.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Unblocked {
+    event: &'static str,
+    team: String,
+    completed: String,
+    tasks: Vec<TaskSummary>,
+}
+
+/// One row of `list_tasks` output.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+struct TaskSummary {
+    id: String,
+    subject: String,
+    status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    owner: Option<String>,
+    blocked_by: Vec<String>,
+    /// Pending, unowned, and every `blockedBy` task completed.
+    available: bool,
+}
+
+impl TaskSummary {
+    /// `blockers_done` says whether every `blockedBy` task is completed.
+    fn new(task: TaskFile, blockers_done: bool) -> Self {
+        let unowned = task.owner.as_deref().is_none_or(str::is_empty);
+        Self {
+            available: task.status == "pending" && unowned && blockers_done,
+            owner: task.owner.filter(|_| !unowned),
+            id: task.id,
+            subject: task.subject,
+            status: task.status,
+            blocked_by: task.blocked_by,
+        }
+    }
+}
+
+/// The result of `list_tasks`, ordered by task ID.
+#[derive(Debug, Serialize, JsonSchema)]
+struct TaskList {
+    tasks: Vec<TaskSummary>,
+}
+
+/// The result of `get_task`: the task as read plus its revision token.
+#[derive(Debug, JsonSchema)]
+struct TaskSnapshot {
+    task: TaskFile,
+    /// Hash of the task file's bytes; changes whenever the file does.
+    revision: String,
+}
+
+/// A successful operation as returned to the client: a summary for humans
+/// and the structured content described by the tool's `outputSchema`.
+trait ToolOutput {
+    fn message(&self) -> String;
+    fn structured(&self) -> anyhow::Result<serde_json::Value>;
+}
+
+impl ToolOutput for TaskOutcome {
+    fn message(&self) -> String {
+        self.message.clone()
+    }
+
+    fn structured(&self) -> anyhow::Result<serde_json::Value> {
+        task_to_json(&self.task)
+    }
+}
+
+impl ToolOutput for TaskSnapshot {
+    fn message(&self) -> String {
+        let task = &self.task;
+        let owner = task.owner.as_deref().filter(|o| !o.is_empty()).unwrap_or("unowned");
+        format!(
+            "Task {} ({}, {owner}, revision {}): {}",
+            task.id, task.status, self.revision, task.subject
+        )
+    }
+
+    fn structured(&self) -> anyhow::Result<serde_json::Value> {
+        Ok(serde_json::json!({
+            "task": task_to_json(&self.task)?,
+            "revision": self.revision,
+        }))
+    }
+}
+
+impl ToolOutput for TaskList {
+    /// One line per task: ID, status, owner (or `-`) and subject.
+    fn message(&self) -> String {
+        let mut message = format!("{} task(s)", self.tasks.len());
+        for task in &self.tasks {
+            let owner = task.owner.as_deref().unwrap_or("-");
+            message.push_str(&format!(
+                "\n{}\t{}\t{owner}\t{}",
+                task.id, task.status, task.subject
+            ));
+        }
+        message
+    }
+
+    fn structured(&self) -> anyhow::Result<serde_json::Value> {
+        Ok(serde_json::to_value(self)?)
+    }
+}
+
+/// How long a claim stays valid without a heartbeat.
+const DEFAULT_LEASE_SECS: u64 = 15 * 60;
+
+/// Longest lease a caller may ask for.
+const MAX_LEASE_SECS: u64 = 24 * 60 * 60;
+
+/// The requested lease duration, or the default. Zero would hand out a lease
+/// that has already expired, so it is rejected along with anything over
+/// [`MAX_LEASE_SECS`].
+fn lease_secs(requested: Option<u64>) -> anyhow::Result<u64> {
+    match requested.unwrap_or(DEFAULT_LEASE_SECS) {
+        secs @ 1..=MAX_LEASE_SECS => Ok(secs),
+        secs => fail!(
+            InvalidArgument,
+            "lease_secs must be between 1 and {MAX_LEASE_SECS}, got {secs}"
+        ),
+    }
+}
+
+impl TaskFile {
+    /// Returns `metadata` as a mutable object, creating it if absent.
+    fn metadata_mut(&mut self) -> anyhow::Result<&mut serde_json::Map<String, serde_json::Value>> {
+        let metadata = self
+            .metadata
+            .get_or_insert_with(|| serde_json::Value::Object(Default::default()));
+        metadata
+            .as_object_mut()
+            .with_context(|| format!("metadata of task {} is not an object", self.id))
+    }
+
+    /// Unix time (seconds) at which the current claim's lease runs out, if
+    /// the task was claimed through this server.
+    fn lease_expires_at(&self) -> Option<u64> {
+        self.metadata.as_ref()?.get("leaseExpiresAt")?.as_u64()
+    }
+
+    fn lease_expired(&self, now: u64) -> bool {
+        self.status == "in_progress" && self.lease_expires_at().is_some_and(|at| at <= now)
+    }
+
+    /// Assigns the task to `owner` and starts a fresh lease.
+    fn claim(&mut self, owner: &str, lease_secs: u64) -> anyhow::Result<()> {
+        let now = unix_now();
+        self.owner = Some(owner.to_string());
+        self.status = "in_progress".to_string();
+        let metadata = self.metadata_mut()?;
+        metadata.insert("claimedAt".to_string(), now.into());
+        metadata.insert("leaseExpiresAt".to_string(), now.saturating_add(lease_secs).into());
+        Ok(())
+    }
+
+    /// Drops lease bookkeeping once the task is no longer held by anyone.
+    fn clear_lease(&mut self, keep_claimed_at: bool) {
+        if let Some(metadata) = self.metadata.as_mut().and_then(|m| m.as_object_mut()) {
+            metadata.remove("leaseExpiresAt");
+            if !keep_claimed_at {
+                metadata.remove("claimedAt");
+            }
+        }
+    }
+}
+
+/// The team a tool call acts on and how to wait for its lock; flattened into
+/// every tool's parameters.
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+struct TeamArgs {
+    #[schemars(
+        description = "Team name (defaults to the configured team, or the only team directory)"
+    )]
+    team: Option<String>,
+    #[schemars(
+        description = "Wait for the team lock (default true); false fails at once with LOCK_BUSY"
+    )]
+    wait: Option<bool>,
 }
 
 #[derive(Debug, Deserialize, JsonSchema)]
@@ -38,250 +268,3000 @@ struct SafeClaimParams {
     task_id: String,
     #[schemars(description = "Agent name claiming the task")]
     owner: String,
-    #[schemars(description = "Team name (defaults to first directory in ~/.claude/tasks/)")]
-    team: Option<String>,
+    #[serde(flatten)]
+    target: TeamArgs,
+    #[serde(default)]
+    #[schemars(description = "Claim even if some blockedBy tasks are not completed")]
+    force: bool,
+    #[schemars(
+        description = "Lease duration in seconds before the claim can be taken over (default 900, at most 86400)"
+    )]
+    lease_secs: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct SafeReleaseParams {
+    #[schemars(description = "Task ID to release")]
+    task_id: String,
+    #[schemars(description = "Agent name that currently owns the task")]
+    owner: String,
+    #[serde(flatten)]
+    target: TeamArgs,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct SafeCompleteParams {
+    #[schemars(description = "Task ID to complete")]
+    task_id: String,
+    #[schemars(description = "Agent name that currently owns the task")]
+    owner: String,
+    #[serde(flatten)]
+    target: TeamArgs,
+    #[schemars(description = "Optional summary of the outcome, stored as metadata.result")]
+    result: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct ClaimNextParams {
+    #[schemars(description = "Agent name claiming the task")]
+    owner: String,
+    #[serde(flatten)]
+    target: TeamArgs,
+    #[schemars(
+        description = "Only consider tasks whose subject or description contains this text (case-insensitive)"
+    )]
+    filter: Option<String>,
+    #[schemars(
+        description = "Lease duration in seconds before the claim can be taken over (default 900, at most 86400)"
+    )]
+    lease_secs: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct HeartbeatParams {
+    #[schemars(description = "Task ID whose lease to extend")]
+    task_id: String,
+    #[schemars(description = "Agent name that currently owns the task")]
+    owner: String,
+    #[serde(flatten)]
+    target: TeamArgs,
+    #[schemars(
+        description = "New lease duration in seconds from now (default 900, at most 86400)"
+    )]
+    lease_secs: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct GetTaskParams {
+    #[schemars(description = "Task ID to read")]
+    task_id: String,
+    #[serde(flatten)]
+    target: TeamArgs,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct CreateTaskParams {
+    #[schemars(description = "Brief title in imperative form, e.g. \"Write unit tests\"")]
+    subject: String,
+    #[schemars(description = "What needs to be done")]
+    description: String,
+    #[serde(alias = "activeForm")]
+    #[schemars(
+        description = "Present continuous form shown while the task is in progress, e.g. \"Writing unit tests\""
+    )]
+    active_form: String,
+    #[serde(default, alias = "blockedBy")]
+    #[schemars(
+        description = "IDs of existing tasks that must be completed first; the new task is added to their blocks"
+    )]
+    blocked_by: Vec<String>,
+    #[schemars(description = "Optional metadata object")]
+    metadata: Option<serde_json::Value>,
+    #[serde(flatten)]
+    target: TeamArgs,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct CheckTeamParams {
+    #[serde(flatten)]
+    target: TeamArgs,
+    #[serde(default)]
+    #[schemars(
+        description = "Fix the mechanical issues (dangling, one-sided or duplicate references, owners on pending tasks) under the exclusive lock"
+    )]
+    repair: bool,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct DependencyParams {
+    #[schemars(description = "Task ID that waits (its blockedBy list changes)")]
+    task_id: String,
+    #[schemars(description = "Task ID that must be completed first (its blocks list changes)")]
+    blocker_id: String,
+    #[serde(flatten)]
+    target: TeamArgs,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct SafeUpdateParams {
+    #[schemars(description = "Task ID to update")]
+    task_id: String,
+    #[schemars(
+        description = "Revision returned by get_task; the update fails with CONFLICT if the task changed since"
+    )]
+    expected_revision: String,
+    #[schemars(
+        description = "JSON merge patch (RFC 7396) applied to the task, e.g. {\"description\": \"...\", \"metadata\": {\"notes\": null}}. Rejected with INVALID_ARGUMENT: id, owner and status (use the claim tools), blocks and blockedBy (use add_dependency/remove_dependency), metadata.leaseExpiresAt and metadata.claimedAt (use heartbeat), and metadata given as anything but an object."
+    )]
+    patch: serde_json::Value,
+    #[serde(flatten)]
+    target: TeamArgs,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct ListTasksParams {
+    #[serde(flatten)]
+    target: TeamArgs,
+    #[schemars(description = "Only list tasks with this status, e.g. pending")]
+    status: Option<String>,
+    #[schemars(description = "Only list tasks owned by this agent")]
+    owner: Option<String>,
+    #[serde(default)]
+    #[schemars(
+        description = "Only list tasks that can be claimed now: pending, unowned, and with all blockedBy tasks completed"
+    )]
+    available_only: bool,
 }
 
 #[derive(Clone)]
 struct SafeTaskClaim {
     tool_router: ToolRouter<Self>,
+    /// Root directory holding one subdirectory per team.
+    tasks_dir: PathBuf,
+    config: Config,
+    /// Cancellation of the request being served; each call runs on its own
+    /// clone with the request's token installed by `run_blocking`.
+    cancel: CancellationToken,
+    /// Least severe log notification the client wants (`logging/setLevel`).
+    log_level: Arc<Mutex<LoggingLevel>>,
 }
 
 impl SafeTaskClaim {
-    fn new() -> Self {
+    fn new(tasks_dir: PathBuf, config: Config) -> Self {
         Self {
             tool_router: Self::tool_router(),
+            tasks_dir,
+            config,
+            cancel: CancellationToken::new(),
+            log_level: Arc::new(Mutex::new(LoggingLevel::Debug)),
         }
     }
 
-    fn tasks_dir() -> PathBuf {
-        dirs::home_dir()
-            .unwrap_or_else(|| PathBuf::from("/tmp"))
-            .join(".claude/tasks")
-    }
-
-    fn resolve_team(team: Option<&str>) -> anyhow::Result<String> {
-        if let Some(t) = team {
-            return Ok(t.to_string());
-        }
-        let tasks_dir = Self::tasks_dir();
-        let entries = fs::read_dir(&tasks_dir)
-            .with_context(|| format!("cannot read {}", tasks_dir.display()))?;
-        for entry in entries {
-            let entry = entry?;
-            if entry.file_type()?.is_dir() {
-                if let Some(name) = entry.file_name().to_str() {
-                    return Ok(name.to_string());
-                }
-            }
+    /// Picks the team for a tool call: the `team` parameter, then the
+    /// configured default (`SAFE_TASK_CLAIM_TEAM` or the config file), then
+    /// the only team directory under the tasks root.
+    fn resolve_team(&self, team: Option<&str>) -> anyhow::Result<Ident> {
+        if let Some(t) = team.or(self.config.team.as_deref()) {
+            return Ident::parse("team", t);
         }
-        bail!("no team directories found in {}", tasks_dir.display());
+        only_team(&self.tasks_dir)
     }
 
-    fn do_claim(&self, params: SafeClaimParams) -> anyhow::Result<String> {
-        let team = Self::resolve_team(params.team.as_deref())?;
-        let team_dir = Self::tasks_dir().join(&team);
+    /// Resolves the team directory and runs `f` on it while holding the
+    /// team's `.lock` in `mode`. With `wait: Some(false)` a busy lock fails
+    /// immediately; otherwise it is retried until the configured deadline or
+    /// until the request is cancelled.
+    fn with_team_lock<T>(
+        &self,
+        team: Option<&str>,
+        wait: Option<bool>,
+        mode: LockMode,
+        f: impl FnOnce(&Path) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let team = self.resolve_team(team)?;
+        let team_dir = resolve_under(&self.tasks_dir, team.as_str())?;
         if !team_dir.is_dir() {
-            bail!("team directory not found: {}", team_dir.display());
+            fail!(NotFound, "team directory not found: {}", team_dir.display());
         }
 
-        let lock_path = team_dir.join(".lock");
-        let task_path = team_dir.join(format!("{}.json", params.task_id));
+        let _lock = TeamLock::acquire(&team_dir.join(".lock"), mode, &self.lock_options(wait))?;
+        f(&team_dir)
+    }
 
-        if !task_path.exists() {
-            bail!("task file not found: {}", task_path.display());
+    fn lock_options(&self, wait: Option<bool>) -> LockOptions {
+        LockOptions {
+            wait: wait.unwrap_or(true),
+            timeout: self.config.lock_timeout(),
+            cancel: self.cancel.clone(),
         }
+    }
 
-        let lock_file = fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(&lock_path)
-            .with_context(|| format!("cannot open lock: {}", lock_path.display()))?;
-
-        lock_exclusive(&lock_file)?;
-        let result = self.claim_under_lock(&task_path, &params.task_id, &params.owner);
-        unlock(&lock_file)?;
+    /// Runs a mutation under the team's exclusive lock.
+    fn with_team_locked<T>(
+        &self,
+        team: Option<&str>,
+        wait: Option<bool>,
+        f: impl FnOnce(&Path) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        self.with_team_lock(team, wait, LockMode::Exclusive, f)
+    }
 
-        result
+    /// Runs a read-only query under a shared team lock: any number of
+    /// readers proceed together, but never while a mutation is in progress.
+    /// `f` must not write to the team directory.
+    fn with_team_read_locked<T>(
+        &self,
+        team: Option<&str>,
+        wait: Option<bool>,
+        f: impl FnOnce(&Path) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        self.with_team_lock(team, wait, LockMode::Shared, f)
     }
 
-    fn claim_under_lock(
+    /// Resolves the task file for `task_id` and runs `f` on it while holding
+    /// the locks of the configured [`LockScope`]: the team's exclusive
+    /// `.lock`, or a shared team lock plus the per-task locks from
+    /// [`Self::lock_task_set`].
+    fn with_task_locked<T>(
         &self,
-        task_path: &PathBuf,
+        team: Option<&str>,
+        wait: Option<bool>,
         task_id: &str,
-        owner: &str,
-    ) -> anyhow::Result<String> {
-        let content = fs::read_to_string(task_path)
-            .with_context(|| format!("cannot read task {task_id}"))?;
-        let mut task: TaskFile =
-            serde_json::from_str(&content).with_context(|| format!("invalid JSON in task {task_id}"))?;
-
-        if let Some(existing) = &task.owner {
-            if !existing.is_empty() {
-                bail!("already claimed by {existing}");
-            }
+        f: impl FnOnce(&Path) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let task_id = Ident::parse("task_id", task_id)?;
+        let resolve = |team_dir: &Path| task_file(team_dir, &task_id);
+        match self.config.lock_scope() {
+            LockScope::Team => self.with_team_locked(team, wait, |team_dir| f(&resolve(team_dir)?)),
+            LockScope::Task => self.with_team_lock(team, wait, LockMode::Shared, |team_dir| {
+                let task_path = resolve(team_dir)?;
+                let _locks = self.lock_task_set(team_dir, &task_id, &task_path, wait)?;
+                f(&task_path)
+            }),
         }
+    }
 
-        match task.status.as_str() {
-            "in_progress" => bail!("task is already in_progress"),
-            "completed" => bail!("task is already completed"),
-            "deleted" => bail!("task is deleted"),
-            _ => {}
-        }
+    /// Takes `.locks/{id}.lock` exclusively for `task_id` and shared for each
+    /// of its `blockedBy` tasks, so a blocker cannot change status while the
+    /// task is being claimed.
+    ///
+    /// Lock ordering: the team `.lock` always comes first, then task locks in
+    /// ascending byte order of their IDs. Every operation that holds more
+    /// than one lock acquires them in this order, so none can deadlock.
+    /// `blockedBy` is only changed under the exclusive team lock, so the
+    /// list read here stays accurate while the shared team lock is held.
+    fn lock_task_set(
+        &self,
+        team_dir: &Path,
+        task_id: &Ident,
+        task_path: &Path,
+        wait: Option<bool>,
+    ) -> anyhow::Result<Vec<TeamLock>> {
+        let task = read_task(task_path, task_id.as_str())?;
+        let mut modes: BTreeMap<String, LockMode> = task
+            .blocked_by
+            .iter()
+            .filter_map(|id| Ident::parse("task_id", id).ok())
+            .map(|id| (id.to_string(), LockMode::Shared))
+            .collect();
+        modes.insert(task_id.to_string(), LockMode::Exclusive);
 
-        task.owner = Some(owner.to_string());
-        task.status = "in_progress".to_string();
+        let lock_dir = team_dir.join(".locks");
+        fs::create_dir_all(&lock_dir)
+            .with_context(|| format!("cannot create {}", lock_dir.display()))?;
+        let opts = self.lock_options(wait);
+        modes
+            .into_iter()
+            .map(|(id, mode)| TeamLock::acquire(&lock_dir.join(format!("{id}.lock")), mode, &opts))
+            .collect()
+    }
 
-        let json = serde_json::to_string_pretty(&task)?;
-        fs::write(task_path, json)
-            .with_context(|| format!("cannot write task {task_id}"))?;
+    fn do_claim(&self, params: SafeClaimParams) -> anyhow::Result<TaskOutcome> {
+        let lease_secs = lease_secs(params.lease_secs)?;
+        let team = params.target.team.as_deref();
+        self.with_task_locked(team, params.target.wait, &params.task_id, |task_path| {
+            self.claim_under_lock(
+                task_path,
+                &params.task_id,
+                &params.owner,
+                params.force,
+                lease_secs,
+            )
+        })
+    }
 
-        Ok(format!("Claimed task {task_id}: {}", task.subject))
+    fn do_release(&self, params: SafeReleaseParams) -> anyhow::Result<TaskOutcome> {
+        let team = params.target.team.as_deref();
+        self.with_task_locked(team, params.target.wait, &params.task_id, |task_path| {
+            self.release_under_lock(task_path, &params.task_id, &params.owner)
+        })
     }
-}
 
-fn lock_exclusive(file: &fs::File) -> anyhow::Result<()> {
-    use std::os::unix::io::AsRawFd;
-    let fd = file.as_raw_fd();
-    let ret = unsafe { libc::flock(fd, libc::LOCK_EX) };
-    if ret != 0 {
-        bail!("flock failed: {}", io::Error::last_os_error());
+    fn do_complete(&self, params: SafeCompleteParams) -> anyhow::Result<TaskOutcome> {
+        let team = params.target.team.as_deref();
+        self.with_task_locked(team, params.target.wait, &params.task_id, |task_path| {
+            self.complete_under_lock(
+                task_path,
+                &params.task_id,
+                &params.owner,
+                params.result.as_deref(),
+            )
+        })
     }
-    Ok(())
-}
 
-fn unlock(file: &fs::File) -> anyhow::Result<()> {
-    use std::os::unix::io::AsRawFd;
-    let fd = file.as_raw_fd();
-    let ret = unsafe { libc::flock(fd, libc::LOCK_UN) };
-    if ret != 0 {
-        bail!("flock failed: {}", io::Error::last_os_error());
+    fn do_claim_next(&self, params: ClaimNextParams) -> anyhow::Result<TaskOutcome> {
+        let lease_secs = lease_secs(params.lease_secs)?;
+        self.with_team_locked(params.target.team.as_deref(), params.target.wait, |team_dir| {
+            self.claim_next_under_lock(
+                team_dir,
+                &params.owner,
+                params.filter.as_deref(),
+                lease_secs,
+            )
+        })
     }
-    Ok(())
-}
 
-#[tool_router]
-impl SafeTaskClaim {
-    #[tool(description = "Atomically claim a task with file locking. Rejects if already claimed, in_progress, or completed.")]
-    async fn safe_claim(&self, Parameters(params): Parameters<SafeClaimParams>) -> String {
-        match self.do_claim(params) {
-            Ok(msg) => msg,
-            Err(e) => format!("Error: {e}"),
+    fn do_heartbeat(&self, params: HeartbeatParams) -> anyhow::Result<TaskOutcome> {
+        let lease_secs = lease_secs(params.lease_secs)?;
+        let team = params.target.team.as_deref();
+        self.with_task_locked(team, params.target.wait, &params.task_id, |task_path| {
+            self.heartbeat_under_lock(
+                task_path,
+                &params.task_id,
+                &params.owner,
+                lease_secs,
+            )
+        })
+    }
+
+    /// Writes `task` back unless the request has been cancelled, returning
+    /// the new revision. Every single-file mutation goes through here, so a
+    /// cancelled request that got the lock after all still leaves the file
+    /// untouched.
+    fn commit(&self, task_path: &Path, task_id: &str, task: &TaskFile) -> anyhow::Result<String> {
+        self.check_cancelled()?;
+        write_task(task_path, task_id, task)
+    }
+
+    /// Fails with `CANCELLED` once the request has been cancelled. Mutations
+    /// that write several files call this once before the first write, so
+    /// they either happen in full or not at all.
+    fn check_cancelled(&self) -> anyhow::Result<()> {
+        if self.cancel.is_cancelled() {
+            fail!(Cancelled, "request cancelled; no task was modified");
         }
+        Ok(())
     }
-}
 
-#[tool_handler(router = self.tool_router)]
-impl ServerHandler for SafeTaskClaim {
-    fn get_info(&self) -> ServerInfo {
-        ServerInfo {
-            instructions: Some(
-                "Safe task claiming with file locking. Use safe_claim before starting work on any task to prevent race conditions."
-                    .into(),
-            ),
-            capabilities: ServerCapabilities::builder().enable_tools().build(),
-            ..Default::default()
+    fn do_create(&self, params: CreateTaskParams) -> anyhow::Result<TaskOutcome> {
+        if params.metadata.as_ref().is_some_and(|m| !m.is_object()) {
+            fail!(InvalidArgument, "metadata must be a JSON object");
+        }
+        let mut blocker_ids: Vec<Ident> = Vec::new();
+        for id in &params.blocked_by {
+            let id = Ident::parse("blockedBy", id)?;
+            if !blocker_ids.contains(&id) {
+                blocker_ids.push(id);
+            }
         }
+        self.with_team_locked(params.target.team.as_deref(), params.target.wait, |team_dir| {
+            self.create_under_lock(team_dir, &params, &blocker_ids)
+        })
     }
-}
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> anyhow::Result<()> {
-    let service = SafeTaskClaim::new();
-    let server = service.serve(stdio()).await?;
-    server.waiting().await?;
-    Ok(())
-}
+    /// Checks the team under a shared lock or, with `repair`, checks and
+    /// fixes it under the exclusive lock.
+    fn do_check(&self, params: CheckTeamParams) -> anyhow::Result<check::Report> {
+        let team = self.resolve_team(params.target.team.as_deref())?;
+        let name = team.as_str();
+        if !params.repair {
+            return self.with_team_read_locked(Some(name), params.target.wait, |team_dir| {
+                check::check(team_dir, name, false)
+            });
+        }
+        self.with_team_locked(Some(name), params.target.wait, |team_dir| {
+            let report = check::check(team_dir, name, true)?;
+            self.check_cancelled()?;
+            for (path, task) in &report.changed {
+                write_task(path, &task.id, task)?;
+            }
+            Ok(report)
+        })
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs;
+    fn do_add_dependency(&self, params: DependencyParams) -> anyhow::Result<TaskOutcome> {
+        let task_id = Ident::parse("task_id", &params.task_id)?;
+        let blocker_id = Ident::parse("blocker_id", &params.blocker_id)?;
+        self.with_team_locked(params.target.team.as_deref(), params.target.wait, |team_dir| {
+            self.add_dependency_under_lock(team_dir, &task_id, &blocker_id)
+        })
+    }
 
-    fn setup_team(dir: &std::path::Path, task_id: &str, status: &str, owner: Option<&str>) {
-        fs::create_dir_all(dir).unwrap();
-        fs::write(dir.join(".lock"), "").unwrap();
-        let task = TaskFile {
-            id: task_id.to_string(),
-            subject: "Test task".to_string(),
-            description: "A test".to_string(),
-            active_form: "Testing".to_string(),
-            status: status.to_string(),
-            owner: owner.map(|s| s.to_string()),
-            blocks: vec![],
-            blocked_by: vec![],
-            metadata: None,
-        };
-        let json = serde_json::to_string_pretty(&task).unwrap();
-        fs::write(dir.join(format!("{task_id}.json")), json).unwrap();
+    fn do_remove_dependency(&self, params: DependencyParams) -> anyhow::Result<TaskOutcome> {
+        let task_id = Ident::parse("task_id", &params.task_id)?;
+        let blocker_id = Ident::parse("blocker_id", &params.blocker_id)?;
+        self.with_team_locked(params.target.team.as_deref(), params.target.wait, |team_dir| {
+            self.remove_dependency_under_lock(team_dir, &task_id, &blocker_id)
+        })
     }
 
-    #[test]
-    fn claim_pending_succeeds() {
-        let tmp = tempfile::tempdir().unwrap();
-        let team_dir = tmp.path().join("test-team");
-        setup_team(&team_dir, "1", "pending", None);
+    /// Applies `params.patch` if the task is still at `expected_revision`.
+    /// Patches may not touch the claim fields, the lease keys in `metadata`,
+    /// or `blocks`/`blockedBy` (those go through the dependency tools under
+    /// the exclusive team lock). `blockedBy` therefore never changes under a
+    /// per-task lock, whose lock set is derived from it.
+    fn do_update(&self, params: SafeUpdateParams) -> anyhow::Result<TaskSnapshot> {
+        let serde_json::Value::Object(patch) = &params.patch else {
+            fail!(InvalidArgument, "patch must be a JSON object");
+        };
+        if let Some(key) = ["id", "owner", "status"].into_iter().find(|k| patch.contains_key(*k)) {
+            fail!(
+                InvalidArgument,
+                "patch must not change {key}; use safe_claim, safe_release or safe_complete"
+            );
+        }
+        if let Some(key) = ["blocks", "blockedBy"].into_iter().find(|k| patch.contains_key(*k)) {
+            fail!(
+                InvalidArgument,
+                "patch must not change {key}; use add_dependency or remove_dependency"
+            );
+        }
+        // The lease belongs to the owner: only claims and heartbeats move it.
+        match patch.get("metadata") {
+            None => {}
+            Some(serde_json::Value::Object(metadata)) => {
+                let lease_key = ["leaseExpiresAt", "claimedAt"]
+                    .into_iter()
+                    .find(|k| metadata.contains_key(*k));
+                if let Some(key) = lease_key {
+                    fail!(InvalidArgument, "patch must not change metadata.{key}; use heartbeat");
+                }
+            }
+            Some(_) => fail!(
+                InvalidArgument,
+                "patch must merge metadata as an object; replacing it would drop the lease"
+            ),
+        }
 
-        let service = SafeTaskClaim::new();
-        let result = service.claim_under_lock(
-            &team_dir.join("1.json"),
-            "1",
-            "agent-a",
-        );
-        assert!(result.is_ok());
-        assert!(result.unwrap().contains("Claimed task 1"));
+        let team = params.target.team.as_deref();
+        self.with_task_locked(team, params.target.wait, &params.task_id, |task_path| {
+            self.update_under_lock(task_path, &params)
+        })
+    }
 
-        let content = fs::read_to_string(team_dir.join("1.json")).unwrap();
-        let task: TaskFile = serde_json::from_str(&content).unwrap();
-        assert_eq!(task.owner.as_deref(), Some("agent-a"));
-        assert_eq!(task.status, "in_progress");
+    /// Reads a task under a shared team lock. Task files are only ever
+    /// replaced by rename, so the snapshot and its revision are consistent
+    /// even while per-task locks let other tasks change.
+    fn do_get(&self, params: GetTaskParams) -> anyhow::Result<TaskSnapshot> {
+        let task_id = Ident::parse("task_id", &params.task_id)?;
+        self.with_team_read_locked(params.target.team.as_deref(), params.target.wait, |team_dir| {
+            let task_path = task_file(team_dir, &task_id)?;
+            let (task, revision) = read_task_revision(&task_path, task_id.as_str())?;
+            Ok(TaskSnapshot { task, revision })
+        })
     }
 
-    #[test]
-    fn claim_already_owned_rejects() {
-        let tmp = tempfile::tempdir().unwrap();
-        let team_dir = tmp.path().join("test-team");
-        setup_team(&team_dir, "2", "pending", Some("agent-b"));
+    fn do_list(&self, params: ListTasksParams) -> anyhow::Result<TaskList> {
+        self.with_team_read_locked(params.target.team.as_deref(), params.target.wait, |team_dir| {
+            let tasks = read_team_tasks(team_dir)?;
+            let completed = completed_ids(&tasks);
+            let mut tasks: Vec<TaskFile> = tasks.into_iter().map(|(_, task)| task).collect();
+            tasks.sort_by_cached_key(|task| id_order(&task.id));
 
-        let service = SafeTaskClaim::new();
-        let result = service.claim_under_lock(
-            &team_dir.join("2.json"),
-            "2",
-            "agent-a",
-        );
-        assert!(result.is_err());
-        assert!(result.unwrap_err().to_string().contains("already claimed by agent-b"));
+            let tasks = tasks
+                .into_iter()
+                .map(|task| {
+                    let blockers_done = task.blocked_by.iter().all(|id| completed.contains(id));
+                    TaskSummary::new(task, blockers_done)
+                })
+                .filter(|row| params.status.as_ref().is_none_or(|s| *s == row.status))
+                .filter(|row| params.owner.is_none() || params.owner == row.owner)
+                .filter(|row| row.available || !params.available_only)
+                .collect();
+            Ok(TaskList { tasks })
+        })
     }
 
-    #[test]
-    fn claim_in_progress_rejects() {
-        let tmp = tempfile::tempdir().unwrap();
-        let team_dir = tmp.path().join("test-team");
-        setup_team(&team_dir, "3", "in_progress", None);
+    fn claim_under_lock(
+        &self,
+        task_path: &Path,
+        task_id: &str,
+        owner: &str,
+        force: bool,
+        lease_secs: u64,
+    ) -> anyhow::Result<TaskOutcome> {
+        let mut task = read_task(task_path, task_id)?;
+        let previous = check_claimable(&task, unix_now())?;
 
-        let service = SafeTaskClaim::new();
-        let result = service.claim_under_lock(
-            &team_dir.join("3.json"),
-            "3",
-            "agent-a",
-        );
-        assert!(result.is_err());
-        assert!(result.unwrap_err().to_string().contains("already in_progress"));
+        if !force {
+            let team_dir = task_path.parent().context("task file has no parent directory")?;
+            let blockers = unfinished_blockers(team_dir, &task);
+            if !blockers.is_empty() {
+                let list: Vec<String> = blockers.iter().map(ToString::to_string).collect();
+                let message = format!("blocked by unfinished tasks: {}", list.join(", "));
+                return Err(ToolError::new(ErrorCode::Blocked, message)
+                    .with_state(task.owner.as_deref(), &task.status)
+                    .with_details(serde_json::json!({ "blockers": blockers }))
+                    .into());
+            }
+        }
+
+        task.claim(owner, lease_secs)?;
+        self.commit(task_path, task_id, &task)?;
+
+        Ok(TaskOutcome::new(claimed_message(&task, previous.as_deref()), task))
     }
 
-    #[test]
-    fn claim_completed_rejects() {
-        let tmp = tempfile::tempdir().unwrap();
-        let team_dir = tmp.path().join("test-team");
-        setup_team(&team_dir, "4", "completed", None);
+    fn claim_next_under_lock(
+        &self,
+        team_dir: &Path,
+        owner: &str,
+        filter: Option<&str>,
+        lease_secs: u64,
+    ) -> anyhow::Result<TaskOutcome> {
+        let now = unix_now();
+        let tasks = read_team_tasks(team_dir)?;
+        let completed = completed_ids(&tasks);
+        let filter = filter.map(str::to_lowercase);
 
-        let service = SafeTaskClaim::new();
-        let result = service.claim_under_lock(
-            &team_dir.join("4.json"),
-            "4",
-            "agent-a",
+        let next = tasks
+            .iter()
+            .filter(|(_, task)| check_claimable(task, now).is_ok())
+            .filter(|(_, task)| {
+                task.blocked_by
+                    .iter()
+                    .all(|id| completed.contains(id))
+            })
+            .filter(|(_, task)| match &filter {
+                Some(needle) => {
+                    task.subject.to_lowercase().contains(needle)
+                        || task.description.to_lowercase().contains(needle)
+                }
+                None => true,
+            })
+            .min_by_key(|(_, task)| claim_order(task));
+
+        let Some((task_path, task)) = next else {
+            fail!(NoTaskAvailable, "no claimable tasks in {}", team_dir.display());
+        };
+
+        let mut task = task.clone();
+        let previous = task.owner.take().filter(|o| !o.is_empty());
+        task.claim(owner, lease_secs)?;
+        self.commit(task_path, &task.id, &task)?;
+
+        Ok(TaskOutcome::new(claimed_message(&task, previous.as_deref()), task))
+    }
+
+    fn update_under_lock(
+        &self,
+        task_path: &Path,
+        params: &SafeUpdateParams,
+    ) -> anyhow::Result<TaskSnapshot> {
+        let task_id = params.task_id.as_str();
+        let (task, current) = read_task_revision(task_path, task_id)?;
+        if current != params.expected_revision {
+            let message = format!(
+                "task {task_id} is at revision {current}, not {}",
+                params.expected_revision
+            );
+            let details = serde_json::json!({ "revision": current, "task": task_to_json(&task)? });
+            return Err(ToolError::new(ErrorCode::Conflict, message)
+                .with_state(task.owner.as_deref(), &task.status)
+                .with_details(details)
+                .into());
+        }
+
+        let mut value = task_to_json(&task)?;
+        merge_patch(&mut value, &params.patch);
+        let key_order = value
+            .as_object()
+            .map(|map| map.keys().cloned().collect())
+            .unwrap_or_default();
+        let mut task: TaskFile = serde_json::from_value(value).map_err(|e| {
+            ToolError::new(ErrorCode::InvalidArgument, format!("patch makes task invalid: {e}"))
+        })?;
+        task.key_order = key_order;
+
+        let revision = self.commit(task_path, task_id, &task)?;
+        Ok(TaskSnapshot { task, revision })
+    }
+
+    /// Allocates the next task ID and creates the task, then adds it to the
+    /// `blocks` list of each task it is blocked by. Runs under the exclusive
+    /// team lock, so other servers cannot allocate the same ID; the
+    /// exclusive create also guards against writers that skip the lock.
+    fn create_under_lock(
+        &self,
+        team_dir: &Path,
+        params: &CreateTaskParams,
+        blocker_ids: &[Ident],
+    ) -> anyhow::Result<TaskOutcome> {
+        let mut blockers = Vec::new();
+        for id in blocker_ids {
+            let path = task_file(team_dir, id)?;
+            let task = read_task(&path, id.as_str())?;
+            blockers.push((path, task));
+        }
+
+        let mut task = TaskFile {
+            subject: params.subject.clone(),
+            description: params.description.clone(),
+            active_form: params.active_form.clone(),
+            status: "pending".to_string(),
+            blocked_by: blocker_ids.iter().map(ToString::to_string).collect(),
+            metadata: params.metadata.clone(),
+            ..Default::default()
+        };
+        self.check_cancelled()?;
+
+        let mark_path = team_dir.join(HIGH_WATER_MARK);
+        let mut id = next_task_id(team_dir, &mark_path)?;
+        loop {
+            task.id = id.to_string();
+            let json = serde_json::to_string_pretty(&task_to_json(&task)?)?;
+            let task_path = team_dir.join(format!("{id}.json"));
+            if create_atomic(&task_path, json.as_bytes())
+                .with_context(|| format!("cannot create task {id}"))?
+            {
+                break;
+            }
+            id = id_after(id)?;
+        }
+        write_atomic(&mark_path, id.to_string().as_bytes())?;
+
+        for (path, mut blocker) in blockers {
+            if !blocker.blocks.contains(&task.id) {
+                blocker.blocks.push(task.id.clone());
+                write_task(&path, &blocker.id, &blocker)?;
+            }
+        }
+
+        Ok(TaskOutcome::new(format!("Created task {id}: {}", task.subject), task))
+    }
+
+    /// Records that `task_id` waits for `blocker_id` on both tasks, unless
+    /// `blocker_id` already (transitively) waits for `task_id`.
+    fn add_dependency_under_lock(
+        &self,
+        team_dir: &Path,
+        task_id: &Ident,
+        blocker_id: &Ident,
+    ) -> anyhow::Result<TaskOutcome> {
+        let task_path = task_file(team_dir, task_id)?;
+        let blocker_path = task_file(team_dir, blocker_id)?;
+
+        let tasks = read_team_tasks(team_dir)?;
+        let graph = dependency_graph(tasks.iter().map(|(_, task)| task));
+        if let Some(path) = dependency_path(&graph, blocker_id.as_str(), task_id.as_str()) {
+            let cycle: Vec<&str> = std::iter::once(task_id.as_str()).chain(path).collect();
+            let message = format!(
+                "task {task_id} cannot wait for {blocker_id}: cycle {}",
+                cycle.join(" -> ")
+            );
+            return Err(ToolError::new(ErrorCode::DependencyCycle, message)
+                .with_details(serde_json::json!({ "cycle": cycle }))
+                .into());
+        }
+
+        let mut task = read_task(&task_path, task_id.as_str())?;
+        let mut blocker = read_task(&blocker_path, blocker_id.as_str())?;
+        let add_blocked_by = !task.blocked_by.iter().any(|id| id == blocker_id.as_str());
+        let add_blocks = !blocker.blocks.iter().any(|id| id == task_id.as_str());
+        self.check_cancelled()?;
+        if add_blocked_by {
+            task.blocked_by.push(blocker_id.to_string());
+            write_task(&task_path, task_id.as_str(), &task)?;
+        }
+        if add_blocks {
+            blocker.blocks.push(task_id.to_string());
+            write_task(&blocker_path, blocker_id.as_str(), &blocker)?;
+        }
+
+        let message = format!("Task {task_id} is blocked by {blocker_id}");
+        Ok(TaskOutcome::new(message, task))
+    }
+
+    /// Removes the dependency of `task_id` on `blocker_id` from both tasks.
+    /// A missing blocker file is tolerated so stale references can be
+    /// cleaned up.
+    fn remove_dependency_under_lock(
+        &self,
+        team_dir: &Path,
+        task_id: &Ident,
+        blocker_id: &Ident,
+    ) -> anyhow::Result<TaskOutcome> {
+        let task_path = task_file(team_dir, task_id)?;
+        let mut task = read_task(&task_path, task_id.as_str())?;
+        let blocker = match task_file(team_dir, blocker_id) {
+            Ok(path) => Some((read_task(&path, blocker_id.as_str())?, path)),
+            Err(_) => None,
+        };
+
+        self.check_cancelled()?;
+        if task.blocked_by.iter().any(|id| id == blocker_id.as_str()) {
+            task.blocked_by.retain(|id| id != blocker_id.as_str());
+            write_task(&task_path, task_id.as_str(), &task)?;
+        }
+        if let Some((mut blocker, path)) = blocker
+            && blocker.blocks.iter().any(|id| id == task_id.as_str())
+        {
+            blocker.blocks.retain(|id| id != task_id.as_str());
+            write_task(&path, blocker_id.as_str(), &blocker)?;
+        }
+
+        let message = format!("Task {task_id} is no longer blocked by {blocker_id}");
+        Ok(TaskOutcome::new(message, task))
+    }
+
+    fn release_under_lock(
+        &self,
+        task_path: &Path,
+        task_id: &str,
+        owner: &str,
+    ) -> anyhow::Result<TaskOutcome> {
+        let mut task = read_task(task_path, task_id)?;
+        check_owner(&task, owner)?;
+
+        match task.status.as_str() {
+            "completed" | "deleted" => return Err(invalid_state(&task)),
+            _ => {}
+        }
+
+        task.owner = None;
+        task.status = "pending".to_string();
+        task.clear_lease(false);
+        self.commit(task_path, task_id, &task)?;
+
+        Ok(TaskOutcome::new(format!("Released task {task_id}: {}", task.subject), task))
+    }
+
+    fn complete_under_lock(
+        &self,
+        task_path: &Path,
+        task_id: &str,
+        owner: &str,
+        result: Option<&str>,
+    ) -> anyhow::Result<TaskOutcome> {
+        let mut task = read_task(task_path, task_id)?;
+        check_owner(&task, owner)?;
+        check_in_progress(&task)?;
+
+        if let Some(result) = result {
+            task.metadata_mut()?
+                .insert("result".to_string(), result.into());
+        }
+
+        task.status = "completed".to_string();
+        task.clear_lease(true);
+        self.commit(task_path, task_id, &task)?;
+
+        let mut message = format!("Completed task {task_id}: {}", task.subject);
+        let team_dir = task_path.parent().context("task file has no team directory")?;
+        let unblocked = newly_unblocked(team_dir, &task);
+        if !unblocked.is_empty() {
+            message.push_str("\nNow available:");
+            for dependent in &unblocked {
+                message.push_str(&format!("\n{}\t{}", dependent.id, dependent.subject));
+            }
+        }
+        let team = team_dir.file_name().unwrap_or_default().to_string_lossy();
+        let mut outcome = TaskOutcome::new(message, task);
+        outcome.unblocked = (!unblocked.is_empty()).then(|| Unblocked {
+            event: "tasks_unblocked",
+            team: team.into_owned(),
+            completed: task_id.to_string(),
+            tasks: unblocked,
+        });
+        Ok(outcome)
+    }
+
+    fn heartbeat_under_lock(
+        &self,
+        task_path: &Path,
+        task_id: &str,
+        owner: &str,
+        lease_secs: u64,
+    ) -> anyhow::Result<TaskOutcome> {
+        let mut task = read_task(task_path, task_id)?;
+        check_owner(&task, owner)?;
+        check_in_progress(&task)?;
+
+        let expires_at = unix_now().saturating_add(lease_secs);
+        task.metadata_mut()?
+            .insert("leaseExpiresAt".to_string(), expires_at.into());
+        self.commit(task_path, task_id, &task)?;
+
+        let message = format!("Extended lease on task {task_id} until {expires_at}");
+        Ok(TaskOutcome::new(message, task))
+    }
+}
+
+/// Maps each task ID to the IDs it waits for. Both sides of a dependency
+/// count, so an edge recorded only in the blocker's `blocks` is still seen.
+fn dependency_graph<'a>(
+    tasks: impl IntoIterator<Item = &'a TaskFile>,
+) -> HashMap<String, HashSet<String>> {
+    let mut graph: HashMap<String, HashSet<String>> = HashMap::new();
+    for task in tasks {
+        graph
+            .entry(task.id.clone())
+            .or_default()
+            .extend(task.blocked_by.iter().cloned());
+        for blocked in &task.blocks {
+            graph.entry(blocked.clone()).or_default().insert(task.id.clone());
+        }
+    }
+    graph
+}
+
+/// Shortest chain of "waits for" edges leading from `from` to `to`,
+/// including both ends, or `None` if `to` is not reachable.
+fn dependency_path<'a>(
+    graph: &'a HashMap<String, HashSet<String>>,
+    from: &'a str,
+    to: &str,
+) -> Option<Vec<&'a str>> {
+    let mut previous: HashMap<&str, &str> = HashMap::new();
+    let mut queue = VecDeque::from([from]);
+    while let Some(id) = queue.pop_front() {
+        if id == to {
+            let mut path = vec![id];
+            while let Some(&prev) = path.last().and_then(|id| previous.get(id)) {
+                path.push(prev);
+            }
+            path.reverse();
+            return Some(path);
+        }
+        let mut next: Vec<&str> = graph.get(id).into_iter().flatten().map(String::as_str).collect();
+        next.sort_unstable();
+        for dep in next {
+            if dep != from && !previous.contains_key(dep) {
+                previous.insert(dep, id);
+                queue.push_back(dep);
+            }
+        }
+    }
+    None
+}
+
+/// File in each team directory recording the highest task ID ever allocated.
+const HIGH_WATER_MARK: &str = ".highwatermark";
+
+/// Next free numeric task ID: one past the larger of the team's high-water
+/// mark and the highest numeric `{id}.json` present. The mark keeps the IDs
+/// of deleted task files from being handed out again, so a mark that does not
+/// parse is an error rather than a fresh start.
+fn next_task_id(team_dir: &Path, mark_path: &Path) -> anyhow::Result<u64> {
+    let mut highest = match fs::read_to_string(mark_path) {
+        Ok(mark) => mark.trim().parse().with_context(|| {
+            format!("{} holds {mark:?}, not a task ID", mark_path.display())
+        })?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e).with_context(|| format!("cannot read {}", mark_path.display())),
+    };
+    let entries = fs::read_dir(team_dir)
+        .with_context(|| format!("cannot read {}", team_dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "json")
+            && let Some(id) = path.file_stem().and_then(|s| s.to_str()?.parse::<u64>().ok())
+        {
+            highest = highest.max(id);
+        }
+    }
+    id_after(highest)
+}
+
+/// The ID following `id`, or an error once numeric IDs run out (e.g. because
+/// of a stray `18446744073709551615.json`).
+fn id_after(id: u64) -> anyhow::Result<u64> {
+    match id.checked_add(1) {
+        Some(next) => Ok(next),
+        None => fail!(Internal, "no task ID after {id} is available"),
+    }
+}
+
+/// Path of an existing task file in `team_dir`.
+fn task_file(team_dir: &Path, task_id: &Ident) -> anyhow::Result<PathBuf> {
+    let task_path = resolve_under(team_dir, &format!("{task_id}.json"))?;
+    if !task_path.is_file() {
+        fail!(NotFound, "task file not found: {}", task_path.display());
+    }
+    Ok(task_path)
+}
+
+/// Returns the single team directory under `tasks_dir`, or an error listing
+/// the candidates when there is more than one.
+fn only_team(tasks_dir: &Path) -> anyhow::Result<Ident> {
+    let entries = fs::read_dir(tasks_dir)
+        .with_context(|| format!("cannot read {}", tasks_dir.display()))?;
+    let mut teams = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_dir()
+            && let Some(name) = entry.file_name().to_str()
+            && let Ok(team) = Ident::parse("team", name)
+        {
+            teams.push(team);
+        }
+    }
+    match teams.len() {
+        0 => fail!(NotFound, "no team directories found in {}", tasks_dir.display()),
+        1 => Ok(teams.remove(0)),
+        _ => {
+            let mut names: Vec<&str> = teams.iter().map(Ident::as_str).collect();
+            names.sort_unstable();
+            let message = format!(
+                "multiple teams in {}: {}; pass team or set {TEAM_ENV}",
+                tasks_dir.display(),
+                names.join(", ")
+            );
+            Err(ToolError::new(ErrorCode::AmbiguousTeam, message)
+                .with_details(serde_json::json!({ "teams": names }))
+                .into())
+        }
+    }
+}
+
+/// Rejects tasks that are owned or no longer pending. An `in_progress` task
+/// whose lease has expired is claimable; its previous owner is returned.
+fn check_claimable(task: &TaskFile, now: u64) -> anyhow::Result<Option<String>> {
+    if task.lease_expired(now) {
+        return Ok(task.owner.clone().filter(|o| !o.is_empty()));
+    }
+
+    if let Some(existing) = &task.owner
+        && !existing.is_empty()
+    {
+        return Err(ToolError::new(
+            ErrorCode::AlreadyClaimed,
+            format!("already claimed by {existing}"),
+        )
+        .with_state(Some(existing), &task.status)
+        .into());
+    }
+
+    match task.status.as_str() {
+        "in_progress" => {
+            Err(ToolError::new(ErrorCode::AlreadyClaimed, "task is already in_progress")
+                .with_state(None, &task.status)
+                .into())
+        }
+        "completed" | "deleted" => Err(invalid_state(task)),
+        _ => Ok(None),
+    }
+}
+
+/// Rejects callers that are not the task's current owner.
+fn check_owner(task: &TaskFile, owner: &str) -> anyhow::Result<()> {
+    match task.owner.as_deref() {
+        None | Some("") => Err(ToolError::new(ErrorCode::NotClaimed, "task is not claimed")
+            .with_state(None, &task.status)
+            .into()),
+        Some(existing) if existing != owner => Err(ToolError::new(
+            ErrorCode::NotOwner,
+            format!("claimed by {existing}, not {owner}"),
+        )
+        .with_state(Some(existing), &task.status)
+        .into()),
+        Some(_) => Ok(()),
+    }
+}
+
+fn check_in_progress(task: &TaskFile) -> anyhow::Result<()> {
+    match task.status.as_str() {
+        "in_progress" => Ok(()),
+        _ => Err(invalid_state(task)),
+    }
+}
+
+/// `INVALID_STATE` error describing why the task's status blocks the
+/// operation.
+fn invalid_state(task: &TaskFile) -> anyhow::Error {
+    let message = match task.status.as_str() {
+        "completed" => "task is already completed".to_string(),
+        "deleted" => "task is deleted".to_string(),
+        other => format!("task is {other}, not in_progress"),
+    };
+    ToolError::new(ErrorCode::InvalidState, message)
+        .with_state(task.owner.as_deref(), &task.status)
+        .into()
+}
+
+fn claimed_message(task: &TaskFile, previous_owner: Option<&str>) -> String {
+    match previous_owner {
+        Some(previous) => format!(
+            "Claimed task {} (lease of {previous} expired): {}",
+            task.id, task.subject
+        ),
+        None => format!("Claimed task {}: {}", task.id, task.subject),
+    }
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// A `blockedBy` entry that is not yet completed.
+#[derive(Debug, Serialize)]
+struct Blocker {
+    id: String,
+    /// `None` when the blocking task file is missing or unreadable.
+    status: Option<String>,
+    owner: Option<String>,
+}
+
+impl std::fmt::Display for Blocker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let status = self.status.as_deref().unwrap_or("missing");
+        match self.owner.as_deref() {
+            Some(owner) if !owner.is_empty() => write!(f, "{} ({status}, owner {owner})", self.id),
+            _ => write!(f, "{} ({status}, unowned)", self.id),
+        }
+    }
+}
+
+/// Reads each of the task's `blockedBy` tasks from `team_dir` and returns
+/// those that are not completed. Missing blockers count as unfinished.
+fn unfinished_blockers(team_dir: &Path, task: &TaskFile) -> Vec<Blocker> {
+    task.blocked_by
+        .iter()
+        .filter_map(|id| {
+            let blocker = Ident::parse("task_id", id)
+                .and_then(|id| resolve_under(team_dir, &format!("{id}.json")))
+                .and_then(|path| read_task(&path, id));
+            match blocker {
+                Ok(blocker) if blocker.status == "completed" => None,
+                Ok(blocker) => Some(Blocker {
+                    id: id.clone(),
+                    status: Some(blocker.status),
+                    owner: blocker.owner,
+                }),
+                Err(_) => Some(Blocker {
+                    id: id.clone(),
+                    status: None,
+                    owner: None,
+                }),
+            }
+        })
+        .collect()
+}
+
+/// The tasks in `completed.blocks` that are now available: they wait for
+/// `completed`, are pending and unowned, and have no other unfinished
+/// blockers. Unreadable dependents are skipped.
+///
+/// Under per-task locking, two blockers of one task can complete at once;
+/// each writes before reading the other, so at least one of them reports it.
+fn newly_unblocked(team_dir: &Path, completed: &TaskFile) -> Vec<TaskSummary> {
+    let mut seen = HashSet::new();
+    let mut unblocked: Vec<TaskSummary> = completed
+        .blocks
+        .iter()
+        .filter(|id| seen.insert(id.as_str()))
+        .filter_map(|id| {
+            let id = Ident::parse("task_id", id).ok()?;
+            read_task(&task_file(team_dir, &id).ok()?, id.as_str()).ok()
+        })
+        .filter(|task| task.blocked_by.contains(&completed.id))
+        .map(|task| {
+            let blockers_done = unfinished_blockers(team_dir, &task).is_empty();
+            TaskSummary::new(task, blockers_done)
+        })
+        .filter(|summary| summary.available)
+        .collect();
+    unblocked.sort_by_cached_key(|summary| id_order(&summary.id));
+    unblocked
+}
+
+/// Sort key for `claim_next`: lowest `metadata.priority` first (tasks without
+/// one last), then [`id_order`].
+fn claim_order(task: &TaskFile) -> (bool, i64, (bool, u64, String)) {
+    let priority = task
+        .metadata
+        .as_ref()
+        .and_then(|m| m.get("priority"))
+        .and_then(|p| p.as_i64());
+    (priority.is_none(), priority.unwrap_or_default(), id_order(&task.id))
+}
+
+/// Sort key for task IDs: numeric IDs in numeric order, then the rest by
+/// their raw value.
+fn id_order(id: &str) -> (bool, u64, String) {
+    let numeric_id = id.parse::<u64>().ok();
+    (numeric_id.is_none(), numeric_id.unwrap_or_default(), id.to_string())
+}
+
+/// IDs of the completed tasks among `tasks`.
+fn completed_ids(tasks: &[(PathBuf, TaskFile)]) -> HashSet<String> {
+    tasks
+        .iter()
+        .filter(|(_, task)| task.status == "completed")
+        .map(|(_, task)| task.id.clone())
+        .collect()
+}
+
+/// Reads every `*.json` task in the team directory. Files that fail to parse
+/// are skipped so one damaged task cannot block the rest of the team.
+fn read_team_tasks(team_dir: &Path) -> anyhow::Result<Vec<(PathBuf, TaskFile)>> {
+    let entries = fs::read_dir(team_dir)
+        .with_context(|| format!("cannot read {}", team_dir.display()))?;
+    let mut tasks = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        let Some(task_id) = path.file_stem().and_then(|s| s.to_str()) else {
+            continue;
+        };
+        if let Ok(task) = read_task(&path, task_id) {
+            tasks.push((path, task));
+        }
+    }
+    Ok(tasks)
+}
+
+fn read_task(task_path: &Path, task_id: &str) -> anyhow::Result<TaskFile> {
+    Ok(read_task_revision(task_path, task_id)?.0)
+}
+
+/// Reads a task along with the [`revision`] of the bytes it was parsed from.
+fn read_task_revision(task_path: &Path, task_id: &str) -> anyhow::Result<(TaskFile, String)> {
+    let content =
+        fs::read_to_string(task_path).with_context(|| format!("cannot read task {task_id}"))?;
+    let revision = revision(content.as_bytes());
+    let value: serde_json::Value =
+        serde_json::from_str(&content).with_context(|| format!("invalid JSON in task {task_id}"))?;
+    let key_order = value
+        .as_object()
+        .map(|map| map.keys().cloned().collect())
+        .unwrap_or_default();
+    let mut task: TaskFile =
+        serde_json::from_value(value).with_context(|| format!("invalid JSON in task {task_id}"))?;
+    task.key_order = key_order;
+    Ok((task, revision))
+}
+
+/// Revision token for a task file: the 64-bit FNV-1a hash of its contents in
+/// hex. Content-based rather than a stored counter, so edits made outside
+/// this server change it too; FNV keeps it stable across builds.
+fn revision(content: &[u8]) -> String {
+    let hash = content.iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("{hash:016x}")
+}
+
+/// Serializes `task` in the key order it was read with. Keys the original
+/// file did not have are appended, except empty ones (`null`, `""`, `[]`)
+/// which would only add noise to an otherwise untouched file.
+fn task_to_json(task: &TaskFile) -> anyhow::Result<serde_json::Value> {
+    let serde_json::Value::Object(mut fields) = serde_json::to_value(task)? else {
+        bail!("task {} did not serialize to an object", task.id);
+    };
+    let mut ordered = serde_json::Map::new();
+    for key in &task.key_order {
+        if let Some(value) = fields.shift_remove(key) {
+            ordered.insert(key.clone(), value);
+        }
+    }
+    for (key, value) in fields {
+        let empty = match &value {
+            serde_json::Value::Null => true,
+            serde_json::Value::String(s) => s.is_empty(),
+            serde_json::Value::Array(a) => a.is_empty(),
+            _ => false,
+        };
+        if task.key_order.is_empty() || !empty {
+            ordered.insert(key, value);
+        }
+    }
+    Ok(serde_json::Value::Object(ordered))
+}
+
+/// Writes `task` and returns the [`revision`] of the new file.
+fn write_task(task_path: &Path, task_id: &str, task: &TaskFile) -> anyhow::Result<String> {
+    let json = serde_json::to_string_pretty(&task_to_json(task)?)?;
+    write_atomic(task_path, json.as_bytes())
+        .with_context(|| format!("cannot write task {task_id}"))?;
+    Ok(revision(json.as_bytes()))
+}
+
+/// Applies an RFC 7396 JSON merge patch: objects merge recursively, `null`
+/// removes a key, and anything else replaces the target value.
+fn merge_patch(target: &mut serde_json::Value, patch: &serde_json::Value) {
+    let serde_json::Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = serde_json::Value::Object(Default::default());
+    }
+    if let serde_json::Value::Object(target) = target {
+        for (key, value) in patch {
+            if value.is_null() {
+                target.shift_remove(key);
+            } else {
+                merge_patch(target.entry(key.clone()).or_insert(serde_json::Value::Null), value);
+            }
+        }
+    }
+}
+
+/// Converts an operation's outcome into a tool result. Successes carry the
+/// [`ToolOutput`] as structured content (matching the tool's `outputSchema`);
+/// failures are reported with `isError: true` and the [`ToolError`].
+/// Builds the tool response. Successes carry the output as
+/// `structuredContent`, which must match the tool's `outputSchema`; errors
+/// therefore leave it empty and add the [`ToolError`] as a second, JSON text
+/// block after the `Error: ...` line.
+fn tool_result(result: anyhow::Result<impl ToolOutput>) -> Result<CallToolResult, McpError> {
+    let result = result.and_then(|output| Ok((output.message(), output.structured()?)));
+    Ok(match result {
+        Ok((message, structured)) => {
+            let mut result = CallToolResult::success(vec![Content::text(message)]);
+            result.structured_content = Some(structured);
+            result
+        }
+        Err(e) => {
+            let error = ToolError::from_anyhow(&e);
+            let mut content = vec![Content::text(format!("Error: {}", error.message))];
+            if let Ok(json) = serde_json::to_string(&error) {
+                content.push(Content::text(json));
+            }
+            CallToolResult::error(content)
+        }
+    })
+}
+
+impl SafeTaskClaim {
+    /// Runs `op` on Tokio's blocking pool. Lock waits and task IO block their
+    /// thread, which on the single-threaded runtime would otherwise stall every
+    /// other request, ping and cancellation until they finish. `ct` is the
+    /// request's cancellation token, which aborts lock waits and writes.
+    async fn run_blocking<O: ToolOutput + Send + 'static>(
+        &self,
+        ct: CancellationToken,
+        op: impl FnOnce(&Self) -> anyhow::Result<O> + Send + 'static,
+    ) -> Result<CallToolResult, McpError> {
+        tool_result(self.spawn_blocking(ct, op).await)
+    }
+
+    /// [`run_blocking`](Self::run_blocking) without converting the result.
+    async fn spawn_blocking<O: Send + 'static>(
+        &self,
+        ct: CancellationToken,
+        op: impl FnOnce(&Self) -> anyhow::Result<O> + Send + 'static,
+    ) -> anyhow::Result<O> {
+        let mut service = self.clone();
+        service.cancel = ct;
+        tokio::task::spawn_blocking(move || op(&service))
+            .await
+            .unwrap_or_else(|e| Err(anyhow::anyhow!("task operation failed: {e}")))
+    }
+
+    /// Tells the client which tasks a completion made available, unless it
+    /// raised the log level above `info`. Delivery is best effort.
+    async fn notify_unblocked(&self, peer: &Peer<RoleServer>, unblocked: &Unblocked) {
+        let min_level = *self.log_level.lock().unwrap_or_else(|e| e.into_inner());
+        if (LoggingLevel::Info as u8) < (min_level as u8) {
+            return;
+        }
+        let Ok(data) = serde_json::to_value(unblocked) else {
+            return;
+        };
+        let _ = peer
+            .notify_logging_message(LoggingMessageNotificationParam {
+                level: LoggingLevel::Info,
+                logger: Some(env!("CARGO_PKG_NAME").to_string()),
+                data,
+            })
+            .await;
+    }
+}
+
+#[tool_router]
+impl SafeTaskClaim {
+    #[tool(
+        description = "Atomically claim a task with file locking. Rejects if already claimed, in_progress, or completed, or if any blockedBy task is not completed (unless force is set).",
+        output_schema = cached_schema_for_type::<TaskFile>()
+    )]
+    async fn safe_claim(
+        &self,
+        Parameters(params): Parameters<SafeClaimParams>,
+        ct: CancellationToken,
+    ) -> Result<CallToolResult, McpError> {
+        self.run_blocking(ct, move |service| service.do_claim(params)).await
+    }
+
+    #[tool(
+        description = "Atomically release a task you own, clearing the owner and resetting status to pending. Rejects if the task is owned by someone else.",
+        output_schema = cached_schema_for_type::<TaskFile>()
+    )]
+    async fn safe_release(
+        &self,
+        Parameters(params): Parameters<SafeReleaseParams>,
+        ct: CancellationToken,
+    ) -> Result<CallToolResult, McpError> {
+        self.run_blocking(ct, move |service| service.do_release(params)).await
+    }
+
+    #[tool(
+        description = "Atomically mark an in_progress task you own as completed, optionally recording a result summary in metadata. Rejects if the task is owned by someone else. The response lists the tasks this made available to claim, which are also announced in an info-level log notification.",
+        output_schema = cached_schema_for_type::<TaskFile>()
+    )]
+    async fn safe_complete(
+        &self,
+        Parameters(params): Parameters<SafeCompleteParams>,
+        ct: CancellationToken,
+        peer: Peer<RoleServer>,
+    ) -> Result<CallToolResult, McpError> {
+        let result = self.spawn_blocking(ct, move |service| service.do_complete(params)).await;
+        if let Ok(TaskOutcome {
+            unblocked: Some(unblocked),
+            ..
+        }) = &result
+        {
+            self.notify_unblocked(&peer, unblocked).await;
+        }
+        tool_result(result)
+    }
+
+    #[tool(
+        description = "Atomically pick and claim the next available task: pending, unowned, with all blockedBy tasks completed. Ordered by metadata.priority (lowest first), then numeric task ID.",
+        output_schema = cached_schema_for_type::<TaskFile>()
+    )]
+    async fn claim_next(
+        &self,
+        Parameters(params): Parameters<ClaimNextParams>,
+        ct: CancellationToken,
+    ) -> Result<CallToolResult, McpError> {
+        self.run_blocking(ct, move |service| service.do_claim_next(params)).await
+    }
+
+    #[tool(
+        description = "Extend the lease on an in_progress task you own. Claims whose lease expires can be taken over by other agents, so call this periodically while working.",
+        output_schema = cached_schema_for_type::<TaskFile>()
+    )]
+    async fn heartbeat(
+        &self,
+        Parameters(params): Parameters<HeartbeatParams>,
+        ct: CancellationToken,
+    ) -> Result<CallToolResult, McpError> {
+        self.run_blocking(ct, move |service| service.do_heartbeat(params)).await
+    }
+
+    #[tool(
+        description = "Read a task under the team lock, never observing a partial write. Returns the task plus a revision token that changes whenever the task file does.",
+        output_schema = cached_schema_for_type::<TaskSnapshot>()
+    )]
+    async fn get_task(
+        &self,
+        Parameters(params): Parameters<GetTaskParams>,
+        ct: CancellationToken,
+    ) -> Result<CallToolResult, McpError> {
+        self.run_blocking(ct, move |service| service.do_get(params)).await
+    }
+
+    #[tool(
+        description = "Create a pending task with the next free numeric ID, allocated under the team lock so concurrent creations never collide. Tasks listed in blockedBy get the new task added to their blocks.",
+        output_schema = cached_schema_for_type::<TaskFile>()
+    )]
+    async fn create_task(
+        &self,
+        Parameters(params): Parameters<CreateTaskParams>,
+        ct: CancellationToken,
+    ) -> Result<CallToolResult, McpError> {
+        self.run_blocking(ct, move |service| service.do_create(params)).await
+    }
+
+    #[tool(
+        description = "Check a team directory for damage: invalid task files, ID mismatches, dangling or one-sided blocks/blockedBy, dependency cycles, and owner/status mismatches. Each issue has a severity; with repair set, mechanical issues are fixed under the exclusive lock.",
+        output_schema = cached_schema_for_type::<check::Report>()
+    )]
+    async fn check_team(
+        &self,
+        Parameters(params): Parameters<CheckTeamParams>,
+        ct: CancellationToken,
+    ) -> Result<CallToolResult, McpError> {
+        self.run_blocking(ct, move |service| service.do_check(params)).await
+    }
+
+    #[tool(
+        description = "Make task_id wait for blocker_id, updating task_id's blockedBy and blocker_id's blocks together under the team lock. Rejects dependencies that would create a cycle and reports the cycle.",
+        output_schema = cached_schema_for_type::<TaskFile>()
+    )]
+    async fn add_dependency(
+        &self,
+        Parameters(params): Parameters<DependencyParams>,
+        ct: CancellationToken,
+    ) -> Result<CallToolResult, McpError> {
+        self.run_blocking(ct, move |service| service.do_add_dependency(params)).await
+    }
+
+    #[tool(
+        description = "Remove the dependency of task_id on blocker_id from both tasks' blockedBy and blocks under the team lock.",
+        output_schema = cached_schema_for_type::<TaskFile>()
+    )]
+    async fn remove_dependency(
+        &self,
+        Parameters(params): Parameters<DependencyParams>,
+        ct: CancellationToken,
+    ) -> Result<CallToolResult, McpError> {
+        self.run_blocking(ct, move |service| service.do_remove_dependency(params)).await
+    }
+
+    #[tool(
+        description = "Edit a task (subject, description, metadata, ...) with a JSON merge patch, but only if it is still at the revision returned by get_task. Fails with CONFLICT and the current task otherwise, so concurrent edits are never lost.",
+        output_schema = cached_schema_for_type::<TaskSnapshot>()
+    )]
+    async fn safe_update(
+        &self,
+        Parameters(params): Parameters<SafeUpdateParams>,
+        ct: CancellationToken,
+    ) -> Result<CallToolResult, McpError> {
+        self.run_blocking(ct, move |service| service.do_update(params)).await
+    }
+
+    #[tool(
+        description = "List the team's tasks under a shared lock, optionally filtered by status, owner, or availability (pending, unowned, all blockedBy tasks completed). Use this to see what can be claimed.",
+        output_schema = cached_schema_for_type::<TaskList>()
+    )]
+    async fn list_tasks(
+        &self,
+        Parameters(params): Parameters<ListTasksParams>,
+        ct: CancellationToken,
+    ) -> Result<CallToolResult, McpError> {
+        self.run_blocking(ct, move |service| service.do_list(params)).await
+    }
+}
+
+#[tool_handler(router = self.tool_router)]
+impl ServerHandler for SafeTaskClaim {
+    fn get_info(&self) -> ServerInfo {
+        ServerInfo {
+            instructions: Some(
+                "Safe task claiming with file locking. Use create_task to add tasks, list_tasks to see what is available, get_task to read a task, safe_update to edit it and add_dependency/remove_dependency to change what it waits for, and safe_claim (or claim_next to pick the next available task) before starting work on any task to prevent race conditions, heartbeat to keep the claim's lease alive, safe_release to give a claimed task back, and safe_complete to finish it. Use check_team to find (and repair) damaged task files and dependencies."
+                    .into(),
+            ),
+            capabilities: ServerCapabilities::builder()
+                .enable_tools()
+                .enable_logging()
+                .build(),
+            ..Default::default()
+        }
+    }
+
+    async fn set_level(
+        &self,
+        request: SetLevelRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<(), McpError> {
+        *self.log_level.lock().unwrap_or_else(|e| e.into_inner()) = request.level;
+        Ok(())
+    }
+}
+
+/// `safe-task-claim check [--repair]`: prints the report for the configured
+/// (or only) team and exits with status 1 if errors remain.
+fn run_check(service: &SafeTaskClaim, repair: bool) -> anyhow::Result<()> {
+    let report = service.do_check(CheckTeamParams {
+        target: TeamArgs::default(),
+        repair,
+    })?;
+    println!("{}", report.message());
+    if report.has_errors() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse(std::env::args().skip(1))?;
+    let config = Config::load(&args)?;
+    let service = SafeTaskClaim::new(config.tasks_dir()?, config);
+    if args.check {
+        return run_check(&service, args.repair);
+    }
+    let server = service.serve(stdio()).await?;
+    server.waiting().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn test_service(tasks_dir: &std::path::Path) -> SafeTaskClaim {
+        SafeTaskClaim::new(tasks_dir.to_path_buf(), Config::default())
+    }
+
+    fn task_scoped_service(tasks_dir: &std::path::Path) -> SafeTaskClaim {
+        let config = Config {
+            lock_scope: Some(LockScope::Task),
+            ..Default::default()
+        };
+        SafeTaskClaim::new(tasks_dir.to_path_buf(), config)
+    }
+
+    fn setup_team(dir: &std::path::Path, task_id: &str, status: &str, owner: Option<&str>) {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join(".lock"), "").unwrap();
+        let task = TaskFile {
+            id: task_id.to_string(),
+            subject: "Test task".to_string(),
+            description: "A test".to_string(),
+            active_form: "Testing".to_string(),
+            status: status.to_string(),
+            owner: owner.map(|s| s.to_string()),
+            ..Default::default()
+        };
+        let json = serde_json::to_string_pretty(&task).unwrap();
+        fs::write(dir.join(format!("{task_id}.json")), json).unwrap();
+    }
+
+    fn edit_task(dir: &std::path::Path, task_id: &str, f: impl FnOnce(&mut TaskFile)) {
+        let path = dir.join(format!("{task_id}.json"));
+        let mut task: TaskFile = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        f(&mut task);
+        fs::write(path, serde_json::to_string_pretty(&task).unwrap()).unwrap();
+    }
+
+    /// The [`ToolError`] JSON block of a failed tool result.
+    fn error_json(result: &CallToolResult) -> serde_json::Value {
+        let text = &result.content[1].as_text().unwrap().text;
+        serde_json::from_str(text).unwrap()
+    }
+
+    fn load_task(dir: &std::path::Path, task_id: &str) -> TaskFile {
+        let content = fs::read_to_string(dir.join(format!("{task_id}.json"))).unwrap();
+        serde_json::from_str(&content).unwrap()
+    }
+
+    #[test]
+    fn claim_pending_succeeds() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("test-team");
+        setup_team(&team_dir, "1", "pending", None);
+
+        let service = test_service(tmp.path());
+        let result = service.claim_under_lock(
+            &team_dir.join("1.json"),
+            "1",
+            "agent-a",
+            false,
+            60,
+        );
+        assert!(result.is_ok());
+        assert!(result.unwrap().message.contains("Claimed task 1"));
+
+        let content = fs::read_to_string(team_dir.join("1.json")).unwrap();
+        let task: TaskFile = serde_json::from_str(&content).unwrap();
+        assert_eq!(task.owner.as_deref(), Some("agent-a"));
+        assert_eq!(task.status, "in_progress");
+    }
+
+    #[test]
+    fn claim_already_owned_rejects() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("test-team");
+        setup_team(&team_dir, "2", "pending", Some("agent-b"));
+
+        let service = test_service(tmp.path());
+        let result = service.claim_under_lock(
+            &team_dir.join("2.json"),
+            "2",
+            "agent-a",
+            false,
+            60,
+        );
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("already claimed by agent-b"));
+    }
+
+    #[test]
+    fn claim_in_progress_rejects() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("test-team");
+        setup_team(&team_dir, "3", "in_progress", None);
+
+        let service = test_service(tmp.path());
+        let result = service.claim_under_lock(
+            &team_dir.join("3.json"),
+            "3",
+            "agent-a",
+            false,
+            60,
+        );
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("already in_progress"));
+    }
+
+    #[test]
+    fn claim_completed_rejects() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("test-team");
+        setup_team(&team_dir, "4", "completed", None);
+
+        let service = test_service(tmp.path());
+        let result = service.claim_under_lock(
+            &team_dir.join("4.json"),
+            "4",
+            "agent-a",
+            false,
+            60,
+        );
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("already completed"));
+    }
+
+    #[test]
+    fn release_own_claim_succeeds() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("test-team");
+        setup_team(&team_dir, "5", "in_progress", Some("agent-a"));
+
+        let service = test_service(tmp.path());
+        let result = service.release_under_lock(&team_dir.join("5.json"), "5", "agent-a");
+        assert!(result.unwrap().message.contains("Released task 5"));
+
+        let content = fs::read_to_string(team_dir.join("5.json")).unwrap();
+        let task: TaskFile = serde_json::from_str(&content).unwrap();
+        assert_eq!(task.owner, None);
+        assert_eq!(task.status, "pending");
+    }
+
+    #[test]
+    fn release_other_owner_rejects() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("test-team");
+        setup_team(&team_dir, "6", "in_progress", Some("agent-b"));
+
+        let service = test_service(tmp.path());
+        let result = service.release_under_lock(&team_dir.join("6.json"), "6", "agent-a");
+        assert!(result.unwrap_err().to_string().contains("claimed by agent-b"));
+
+        let content = fs::read_to_string(team_dir.join("6.json")).unwrap();
+        let task: TaskFile = serde_json::from_str(&content).unwrap();
+        assert_eq!(task.owner.as_deref(), Some("agent-b"));
+        assert_eq!(task.status, "in_progress");
+    }
+
+    #[test]
+    fn release_unclaimed_rejects() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("test-team");
+        setup_team(&team_dir, "7", "pending", None);
+
+        let service = test_service(tmp.path());
+        let result = service.release_under_lock(&team_dir.join("7.json"), "7", "agent-a");
+        assert!(result.unwrap_err().to_string().contains("not claimed"));
+    }
+
+    #[test]
+    fn complete_own_claim_records_result() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("test-team");
+        setup_team(&team_dir, "8", "in_progress", Some("agent-a"));
+
+        let service = test_service(tmp.path());
+        let result = service.complete_under_lock(
+            &team_dir.join("8.json"),
+            "8",
+            "agent-a",
+            Some("all tests green"),
+        );
+        assert!(result.unwrap().message.contains("Completed task 8"));
+
+        let content = fs::read_to_string(team_dir.join("8.json")).unwrap();
+        let task: TaskFile = serde_json::from_str(&content).unwrap();
+        assert_eq!(task.status, "completed");
+        assert_eq!(task.owner.as_deref(), Some("agent-a"));
+        assert_eq!(task.metadata.unwrap()["result"], "all tests green");
+    }
+
+    #[test]
+    fn complete_lists_newly_available_tasks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("test-team");
+        setup_team(&team_dir, "1", "in_progress", Some("agent-a"));
+        edit_task(&team_dir, "1", |t| t.blocks = ["2", "3", "4", "5"].map(String::from).into());
+        for id in ["2", "3", "6"] {
+            setup_team(&team_dir, id, "pending", None);
+        }
+        setup_team(&team_dir, "4", "in_progress", Some("agent-b"));
+        edit_task(&team_dir, "2", |t| t.blocked_by = vec!["1".to_string()]);
+        edit_task(&team_dir, "3", |t| t.blocked_by = vec!["1".to_string(), "6".to_string()]);
+        edit_task(&team_dir, "4", |t| t.blocked_by = vec!["1".to_string()]);
+
+        let service = test_service(tmp.path());
+        let outcome = service
+            .complete_under_lock(&team_dir.join("1.json"), "1", "agent-a", None)
+            .unwrap();
+        assert_eq!(outcome.message, "Completed task 1: Test task\nNow available:\n2\tTest task");
+        let unblocked = outcome.unblocked.unwrap();
+        assert_eq!(unblocked.team, "test-team");
+        assert_eq!(unblocked.completed, "1");
+        let ids: Vec<&str> = unblocked.tasks.iter().map(|t| t.id.as_str()).collect();
+        assert_eq!(ids, ["2"]);
+
+        setup_team(&team_dir, "7", "in_progress", Some("agent-a"));
+        let outcome = service
+            .complete_under_lock(&team_dir.join("7.json"), "7", "agent-a", None)
+            .unwrap();
+        assert!(outcome.unblocked.is_none());
+    }
+
+    #[test]
+    fn complete_other_owner_rejects() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("test-team");
+        setup_team(&team_dir, "9", "in_progress", Some("agent-b"));
+
+        let service = test_service(tmp.path());
+        let result = service.complete_under_lock(&team_dir.join("9.json"), "9", "agent-a", None);
+        assert!(result.unwrap_err().to_string().contains("claimed by agent-b"));
+
+        let content = fs::read_to_string(team_dir.join("9.json")).unwrap();
+        let task: TaskFile = serde_json::from_str(&content).unwrap();
+        assert_eq!(task.status, "in_progress");
+    }
+
+    #[test]
+    fn complete_pending_rejects() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("test-team");
+        setup_team(&team_dir, "10", "pending", Some("agent-a"));
+
+        let service = test_service(tmp.path());
+        let result = service.complete_under_lock(&team_dir.join("10.json"), "10", "agent-a", None);
+        assert!(result.unwrap_err().to_string().contains("not in_progress"));
+    }
+
+    #[test]
+    fn claim_next_skips_blocked_and_orders_by_numeric_id() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("test-team");
+        setup_team(&team_dir, "1", "completed", Some("agent-z"));
+        setup_team(&team_dir, "2", "pending", None);
+        setup_team(&team_dir, "3", "pending", None);
+        setup_team(&team_dir, "10", "pending", None);
+        edit_task(&team_dir, "2", |t| t.blocked_by = vec!["1".into(), "3".into()]);
+
+        let service = test_service(tmp.path());
+        let result = service.claim_next_under_lock(&team_dir, "agent-a", None, 60);
+        assert!(result.unwrap().message.contains("Claimed task 3"));
+        let result = service.claim_next_under_lock(&team_dir, "agent-b", None, 60);
+        assert!(result.unwrap().message.contains("Claimed task 10"));
+
+        assert_eq!(load_task(&team_dir, "3").owner.as_deref(), Some("agent-a"));
+        assert_eq!(load_task(&team_dir, "10").owner.as_deref(), Some("agent-b"));
+        assert_eq!(load_task(&team_dir, "2").status, "pending");
+    }
+
+    #[test]
+    fn claim_next_prefers_priority_and_applies_filter() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("test-team");
+        setup_team(&team_dir, "1", "pending", None);
+        setup_team(&team_dir, "2", "pending", None);
+        setup_team(&team_dir, "3", "pending", None);
+        edit_task(&team_dir, "2", |t| t.metadata = Some(serde_json::json!({"priority": 1})));
+        edit_task(&team_dir, "3", |t| t.subject = "Write docs".into());
+
+        let service = test_service(tmp.path());
+        let result = service.claim_next_under_lock(&team_dir, "agent-a", None, 60);
+        assert!(result.unwrap().message.contains("Claimed task 2"));
+        let result = service.claim_next_under_lock(&team_dir, "agent-a", Some("DOCS"), 60);
+        assert!(result.unwrap().message.contains("Claimed task 3"));
+    }
+
+    #[test]
+    fn claim_next_without_candidates_rejects() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("test-team");
+        setup_team(&team_dir, "1", "in_progress", Some("agent-b"));
+
+        let service = test_service(tmp.path());
+        let result = service.claim_next_under_lock(&team_dir, "agent-a", None, 60);
+        assert!(result.unwrap_err().to_string().contains("no claimable tasks"));
+    }
+
+    #[test]
+    fn claim_with_unfinished_blockers_rejects() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("test-team");
+        setup_team(&team_dir, "1", "completed", Some("agent-z"));
+        setup_team(&team_dir, "2", "in_progress", Some("agent-b"));
+        setup_team(&team_dir, "3", "pending", None);
+        edit_task(&team_dir, "3", |t| t.blocked_by = vec!["1".into(), "2".into(), "99".into()]);
+
+        let service = test_service(tmp.path());
+        let result =
+            service.claim_under_lock(&team_dir.join("3.json"), "3", "agent-a", false, 60);
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("2 (in_progress, owner agent-b)"), "{err}");
+        assert!(err.contains("99 (missing, unowned)"), "{err}");
+        assert!(!err.contains("1 ("), "{err}");
+        assert_eq!(load_task(&team_dir, "3").status, "pending");
+    }
+
+    #[test]
+    fn claim_with_unfinished_blockers_and_force_succeeds() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("test-team");
+        setup_team(&team_dir, "1", "pending", None);
+        setup_team(&team_dir, "2", "pending", None);
+        edit_task(&team_dir, "2", |t| t.blocked_by = vec!["1".into()]);
+
+        let service = test_service(tmp.path());
+        let result =
+            service.claim_under_lock(&team_dir.join("2.json"), "2", "agent-a", true, 60);
+        assert!(result.unwrap().message.contains("Claimed task 2"));
+        assert_eq!(load_task(&team_dir, "2").owner.as_deref(), Some("agent-a"));
+    }
+
+    #[test]
+    fn claim_records_lease() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("test-team");
+        setup_team(&team_dir, "1", "pending", None);
+
+        let service = test_service(tmp.path());
+        service
+            .claim_under_lock(&team_dir.join("1.json"), "1", "agent-a", false, 60)
+            .unwrap();
+
+        let metadata = load_task(&team_dir, "1").metadata.unwrap();
+        let claimed_at = metadata["claimedAt"].as_u64().unwrap();
+        assert_eq!(metadata["leaseExpiresAt"].as_u64(), Some(claimed_at + 60));
+    }
+
+    #[test]
+    fn claim_expired_lease_reassigns() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("test-team");
+        setup_team(&team_dir, "1", "in_progress", Some("agent-b"));
+        edit_task(&team_dir, "1", |t| {
+            t.metadata = Some(serde_json::json!({"claimedAt": 1, "leaseExpiresAt": 2}))
+        });
+
+        let service = test_service(tmp.path());
+        let result = service.claim_under_lock(&team_dir.join("1.json"), "1", "agent-a", false, 60);
+        assert!(result.unwrap().message.contains("lease of agent-b expired"));
+
+        let task = load_task(&team_dir, "1");
+        assert_eq!(task.owner.as_deref(), Some("agent-a"));
+        assert!(task.lease_expires_at().unwrap() > 2);
+    }
+
+    #[test]
+    fn claim_live_lease_rejects() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("test-team");
+        setup_team(&team_dir, "1", "pending", None);
+
+        let service = test_service(tmp.path());
+        service
+            .claim_under_lock(&team_dir.join("1.json"), "1", "agent-b", false, 60)
+            .unwrap();
+        let result = service.claim_under_lock(&team_dir.join("1.json"), "1", "agent-a", false, 60);
+        assert!(result.unwrap_err().to_string().contains("already claimed by agent-b"));
+    }
+
+    #[test]
+    fn heartbeat_extends_lease_for_owner_only() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("test-team");
+        setup_team(&team_dir, "1", "in_progress", Some("agent-a"));
+        edit_task(&team_dir, "1", |t| {
+            t.metadata = Some(serde_json::json!({"claimedAt": 1, "leaseExpiresAt": 2}))
+        });
+
+        let service = test_service(tmp.path());
+        let result = service.heartbeat_under_lock(&team_dir.join("1.json"), "1", "agent-b", 60);
+        assert!(result.unwrap_err().to_string().contains("claimed by agent-a"));
+
+        service
+            .heartbeat_under_lock(&team_dir.join("1.json"), "1", "agent-a", 60)
+            .unwrap();
+        let task = load_task(&team_dir, "1");
+        assert!(task.lease_expires_at().unwrap() >= unix_now() + 59);
+        assert_eq!(task.metadata.unwrap()["claimedAt"], 1);
+    }
+
+    #[test]
+    fn lease_secs_outside_bounds_rejects() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("test-team");
+        setup_team(&team_dir, "1", "pending", None);
+        let service = test_service(tmp.path());
+
+        for secs in [0, MAX_LEASE_SECS + 1, u64::MAX] {
+            let params = SafeClaimParams {
+                lease_secs: Some(secs),
+                ..claim_params("1")
+            };
+            let err = service.do_claim(params).unwrap_err();
+            assert_eq!(ToolError::from_anyhow(&err).code, ErrorCode::InvalidArgument, "{secs}");
+        }
+        assert_eq!(load_task(&team_dir, "1").status, "pending");
+
+        let params = SafeClaimParams {
+            lease_secs: Some(MAX_LEASE_SECS),
+            ..claim_params("1")
+        };
+        let task = service.do_claim(params).unwrap().task;
+        assert!(task.lease_expires_at().unwrap() >= unix_now() + MAX_LEASE_SECS - 1);
+    }
+
+    #[test]
+    fn traversal_in_parameters_rejects() {
+        let tmp = tempfile::tempdir().unwrap();
+        setup_team(&tmp.path().join("team"), "3", "pending", None);
+
+        let service = test_service(tmp.path());
+        let result = service.with_task_locked(Some("team"), None, "../../other-team/3", |_| Ok(()));
+        assert!(result.unwrap_err().to_string().contains("invalid task_id"));
+        let result = service.with_task_locked(Some("/etc"), None, "3", |_| Ok(()));
+        assert!(result.unwrap_err().to_string().contains("invalid team"));
+        let result = service.with_team_locked(Some(".."), None, |_| Ok(()));
+        assert!(result.unwrap_err().to_string().contains("invalid team"));
+    }
+
+    #[test]
+    fn traversal_in_blocked_by_counts_as_missing() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("test-team");
+        setup_team(&team_dir, "1", "completed", None);
+        setup_team(&team_dir, "2", "pending", None);
+        edit_task(&team_dir, "2", |t| t.blocked_by = vec!["../test-team/1".into()]);
+
+        let service = test_service(tmp.path());
+        let result =
+            service.claim_under_lock(&team_dir.join("2.json"), "2", "agent-a", false, 60);
+        assert!(result.unwrap_err().to_string().contains("../test-team/1 (missing"));
+    }
+
+    #[test]
+    fn claim_preserves_unknown_fields_and_key_order() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("test-team");
+        fs::create_dir_all(&team_dir).unwrap();
+        let original = r#"{
+  "subject": "Test task",
+  "futureFlag": true,
+  "id": "1",
+  "status": "pending",
+  "createdBy": {"tool": "other", "version": 3},
+  "blockedBy": []
+}"#;
+        fs::write(team_dir.join("1.json"), original).unwrap();
+
+        let service = test_service(tmp.path());
+        service
+            .claim_under_lock(&team_dir.join("1.json"), "1", "agent-a", false, 60)
+            .unwrap();
+
+        let content = fs::read_to_string(team_dir.join("1.json")).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&content).unwrap();
+        let keys: Vec<&str> = value.as_object().unwrap().keys().map(String::as_str).collect();
+        assert_eq!(
+            keys,
+            ["subject", "futureFlag", "id", "status", "createdBy", "blockedBy", "owner", "metadata"]
+        );
+        assert_eq!(value["futureFlag"], true);
+        assert_eq!(value["createdBy"], serde_json::json!({"tool": "other", "version": 3}));
+        assert_eq!(value["status"], "in_progress");
+    }
+
+    #[test]
+    fn only_team_picks_single_directory() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::create_dir(tmp.path().join("alpha")).unwrap();
+        fs::create_dir(tmp.path().join(".hidden")).unwrap();
+        fs::write(tmp.path().join("stray.json"), "{}").unwrap();
+
+        assert_eq!(only_team(tmp.path()).unwrap().as_str(), "alpha");
+    }
+
+    #[test]
+    fn only_team_lists_candidates_when_ambiguous() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::create_dir(tmp.path().join("beta")).unwrap();
+        fs::create_dir(tmp.path().join("alpha")).unwrap();
+
+        let err = only_team(tmp.path()).unwrap_err().to_string();
+        assert!(err.contains("multiple teams"), "{err}");
+        assert!(err.contains("alpha, beta"), "{err}");
+        assert!(err.contains(TEAM_ENV), "{err}");
+    }
+
+    #[test]
+    fn only_team_without_directories_rejects() {
+        let tmp = tempfile::tempdir().unwrap();
+        let err = only_team(tmp.path()).unwrap_err().to_string();
+        assert!(err.contains("no team directories"), "{err}");
+    }
+
+    #[test]
+    fn resolve_team_prefers_parameter_over_config() {
+        let service = SafeTaskClaim::new(
+            PathBuf::from("/nonexistent"),
+            Config {
+                team: Some("configured".to_string()),
+                ..Default::default()
+            },
+        );
+        assert_eq!(service.resolve_team(Some("explicit")).unwrap().as_str(), "explicit");
+        assert_eq!(service.resolve_team(None).unwrap().as_str(), "configured");
+    }
+
+    #[test]
+    fn do_claim_end_to_end_in_tasks_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        setup_team(&tmp.path().join("only-team"), "1", "pending", None);
+
+        let service = test_service(tmp.path());
+        let result = service.do_claim(SafeClaimParams {
+            task_id: "1".to_string(),
+            owner: "agent-a".to_string(),
+            target: TeamArgs::default(),
+            force: false,
+            lease_secs: None,
+        });
+        assert!(result.unwrap().message.contains("Claimed task 1"));
+        assert_eq!(
+            load_task(&tmp.path().join("only-team"), "1").owner.as_deref(),
+            Some("agent-a")
+        );
+
+        let result = service.do_claim(SafeClaimParams {
+            task_id: "1".to_string(),
+            owner: "agent-b".to_string(),
+            target: TeamArgs {
+                team: Some("only-team".to_string()),
+                wait: None,
+            },
+            force: false,
+            lease_secs: None,
+        });
+        assert!(result.unwrap_err().to_string().contains("already claimed by agent-a"));
+    }
+
+    #[test]
+    fn do_claim_missing_task_rejects() {
+        let tmp = tempfile::tempdir().unwrap();
+        setup_team(&tmp.path().join("team"), "1", "pending", None);
+
+        let service = test_service(tmp.path());
+        let result = service.do_claim(SafeClaimParams {
+            task_id: "2".to_string(),
+            owner: "agent-a".to_string(),
+            target: TeamArgs {
+                team: Some("team".to_string()),
+                wait: None,
+            },
+            force: false,
+            lease_secs: None,
+        });
+        assert!(result.unwrap_err().to_string().contains("task file not found"));
+    }
+
+    #[test]
+    fn tool_result_reports_structured_error() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("test-team");
+        setup_team(&team_dir, "1", "in_progress", Some("agent-b"));
+
+        let service = test_service(tmp.path());
+        let result = tool_result(service.do_claim(SafeClaimParams {
+            task_id: "1".to_string(),
+            owner: "agent-a".to_string(),
+            target: TeamArgs::default(),
+            force: false,
+            lease_secs: None,
+        }))
+        .unwrap();
+
+        assert_eq!(result.is_error, Some(true));
+        assert_eq!(result.structured_content, None);
+        assert_eq!(
+            error_json(&result),
+            serde_json::json!({
+                "code": "ALREADY_CLAIMED",
+                "message": "already claimed by agent-b",
+                "owner": "agent-b",
+                "status": "in_progress",
+            })
+        );
+    }
+
+    #[test]
+    fn tool_result_fails_when_output_does_not_serialize() {
+        struct Unserializable;
+        impl ToolOutput for Unserializable {
+            fn message(&self) -> String {
+                "done".to_string()
+            }
+            fn structured(&self) -> anyhow::Result<serde_json::Value> {
+                anyhow::bail!("cannot serialize")
+            }
+        }
+
+        let result = tool_result(Ok(Unserializable)).unwrap();
+        assert_eq!(result.is_error, Some(true));
+        assert_eq!(result.structured_content, None);
+        assert_eq!(error_json(&result)["code"], "INTERNAL");
+    }
+
+    #[test]
+    fn blocked_claim_reports_blockers() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("test-team");
+        setup_team(&team_dir, "1", "in_progress", Some("agent-b"));
+        setup_team(&team_dir, "2", "pending", None);
+        edit_task(&team_dir, "2", |t| t.blocked_by = vec!["1".into()]);
+
+        let service = test_service(tmp.path());
+        let err = service
+            .claim_under_lock(&team_dir.join("2.json"), "2", "agent-a", false, 60)
+            .unwrap_err();
+        let error = ToolError::from_anyhow(&err);
+
+        assert_eq!(error.code, ErrorCode::Blocked);
+        assert_eq!(
+            error.details.unwrap()["blockers"],
+            serde_json::json!([{"id": "1", "status": "in_progress", "owner": "agent-b"}])
         );
-        assert!(result.is_err());
-        assert!(result.unwrap_err().to_string().contains("already completed"));
+    }
+
+    #[test]
+    fn missing_task_reports_not_found() {
+        let tmp = tempfile::tempdir().unwrap();
+        setup_team(&tmp.path().join("team"), "1", "pending", None);
+
+        let service = test_service(tmp.path());
+        let err = service.with_task_locked(None, None, "2", |_| Ok(())).unwrap_err();
+        assert_eq!(ToolError::from_anyhow(&err).code, ErrorCode::NotFound);
+    }
+
+    #[test]
+    fn tool_result_returns_updated_task() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("test-team");
+        setup_team(&team_dir, "1", "pending", None);
+
+        let service = test_service(tmp.path());
+        let result = tool_result(service.do_claim(SafeClaimParams {
+            task_id: "1".to_string(),
+            owner: "agent-a".to_string(),
+            target: TeamArgs::default(),
+            force: false,
+            lease_secs: None,
+        }))
+        .unwrap();
+
+        assert_eq!(result.is_error, Some(false));
+        let text = result.content[0].as_text().unwrap();
+        assert_eq!(text.text, "Claimed task 1: Test task");
+        let task = result.structured_content.unwrap();
+        assert_eq!(task["id"], "1");
+        assert_eq!(task["owner"], "agent-a");
+        assert_eq!(task["status"], "in_progress");
+        assert!(task["metadata"]["leaseExpiresAt"].is_u64());
+    }
+
+    #[test]
+    fn tools_declare_task_output_schema() {
+        let tmp = tempfile::tempdir().unwrap();
+        let service = test_service(tmp.path());
+        for tool in service.tool_router.list_all() {
+            let schema = tool.output_schema.expect("tool has no output schema");
+            assert_eq!(schema["type"], "object", "{}", tool.name);
+            let returns_task = !["list_tasks", "get_task", "safe_update", "check_team"]
+                .contains(&tool.name.as_ref());
+            if returns_task {
+                assert!(schema["properties"]["blockedBy"].is_object(), "{}", tool.name);
+            }
+        }
+    }
+
+    #[test]
+    fn every_tool_accepts_team_and_wait() {
+        let tmp = tempfile::tempdir().unwrap();
+        let service = test_service(tmp.path());
+        for tool in service.tool_router.list_all() {
+            let properties = &tool.input_schema["properties"];
+            assert!(properties["team"]["description"].is_string(), "{}", tool.name);
+            assert!(properties["wait"]["description"].is_string(), "{}", tool.name);
+        }
+
+        let params: SafeClaimParams = serde_json::from_value(serde_json::json!({
+            "task_id": "1",
+            "owner": "agent-a",
+            "team": "alpha",
+            "wait": false,
+            "lease_secs": 60,
+        }))
+        .unwrap();
+        assert_eq!(params.target.team.as_deref(), Some("alpha"));
+        assert_eq!(params.target.wait, Some(false));
+        assert_eq!(params.lease_secs, Some(60));
+    }
+
+    #[test]
+    fn claim_without_wait_on_busy_lock_rejects() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("team");
+        setup_team(&team_dir, "1", "pending", None);
+        let lock_path = team_dir.join(".lock");
+        let _holder =
+            TeamLock::acquire(&lock_path, LockMode::Exclusive, &LockOptions::default()).unwrap();
+
+        let service = test_service(tmp.path());
+        let err = service
+            .do_claim(SafeClaimParams {
+                task_id: "1".to_string(),
+                owner: "agent-a".to_string(),
+                target: TeamArgs {
+                    team: None,
+                    wait: Some(false),
+                },
+                force: false,
+                lease_secs: None,
+            })
+            .unwrap_err();
+        let error = ToolError::from_anyhow(&err);
+        assert_eq!(error.code, ErrorCode::LockBusy);
+        assert_eq!(error.details.unwrap()["holderPid"], std::process::id());
+        assert_eq!(load_task(&team_dir, "1").status, "pending");
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn lock_wait_does_not_block_other_calls() {
+        let tmp = tempfile::tempdir().unwrap();
+        setup_team(&tmp.path().join("busy"), "1", "pending", None);
+        setup_team(&tmp.path().join("idle"), "1", "pending", None);
+        let lock_path = tmp.path().join("busy/.lock");
+        let holder =
+            TeamLock::acquire(&lock_path, LockMode::Exclusive, &LockOptions::default()).unwrap();
+
+        let service = test_service(tmp.path());
+        let claim = |team: &str| SafeClaimParams {
+            task_id: "1".to_string(),
+            owner: "agent-a".to_string(),
+            target: TeamArgs {
+                team: Some(team.to_string()),
+                wait: None,
+            },
+            force: false,
+            lease_secs: None,
+        };
+        let waiting = tokio::spawn({
+            let service = service.clone();
+            let params = claim("busy");
+            async move { service.safe_claim(Parameters(params), CancellationToken::new()).await }
+        });
+        tokio::task::yield_now().await;
+
+        let other = service
+            .safe_claim(Parameters(claim("idle")), CancellationToken::new())
+            .await
+            .unwrap();
+        assert_eq!(other.is_error, Some(false));
+        assert!(!waiting.is_finished());
+
+        drop(holder);
+        let waited = waiting.await.unwrap().unwrap();
+        assert_eq!(waited.is_error, Some(false));
+    }
+
+    #[test]
+    fn readers_share_the_team_lock() {
+        let tmp = tempfile::tempdir().unwrap();
+        setup_team(&tmp.path().join("team"), "1", "pending", None);
+        let service = test_service(tmp.path());
+
+        let inner = service
+            .with_team_read_locked(None, Some(false), |_| {
+                service.with_team_read_locked(None, Some(false), |team_dir| {
+                    read_task(&team_dir.join("1.json"), "1")
+                })
+            })
+            .unwrap();
+        assert_eq!(inner.status, "pending");
+    }
+
+    #[test]
+    fn readers_and_writers_exclude_each_other() {
+        let tmp = tempfile::tempdir().unwrap();
+        setup_team(&tmp.path().join("team"), "1", "pending", None);
+        let service = test_service(tmp.path());
+        let claim = || SafeClaimParams {
+            task_id: "1".to_string(),
+            owner: "agent-a".to_string(),
+            target: TeamArgs {
+                team: None,
+                wait: Some(false),
+            },
+            force: false,
+            lease_secs: None,
+        };
+
+        let err = service
+            .with_team_read_locked(None, None, |_| service.do_claim(claim()))
+            .unwrap_err();
+        assert_eq!(ToolError::from_anyhow(&err).code, ErrorCode::LockBusy);
+
+        let err = service
+            .with_team_locked(None, None, |_| {
+                service.with_team_read_locked(None, Some(false), |_| Ok(()))
+            })
+            .unwrap_err();
+        assert_eq!(ToolError::from_anyhow(&err).code, ErrorCode::LockBusy);
+
+        service.do_claim(claim()).unwrap();
+    }
+
+    fn claim_params(task_id: &str) -> SafeClaimParams {
+        SafeClaimParams {
+            task_id: task_id.to_string(),
+            owner: "agent-a".to_string(),
+            target: TeamArgs {
+                team: None,
+                wait: Some(false),
+            },
+            force: false,
+            lease_secs: None,
+        }
+    }
+
+    fn hold(path: &std::path::Path) -> TeamLock {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        TeamLock::acquire(path, LockMode::Exclusive, &LockOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn per_task_locks_only_block_the_same_task() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("team");
+        setup_team(&team_dir, "1", "pending", None);
+        setup_team(&team_dir, "2", "pending", None);
+        let service = task_scoped_service(tmp.path());
+
+        let _held = hold(&team_dir.join(".locks/1.lock"));
+        service.do_claim(claim_params("2")).unwrap();
+        let err = service.do_claim(claim_params("1")).unwrap_err();
+        assert_eq!(ToolError::from_anyhow(&err).code, ErrorCode::LockBusy);
+    }
+
+    #[test]
+    fn per_task_claim_locks_its_blockers() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("team");
+        setup_team(&team_dir, "1", "completed", None);
+        setup_team(&team_dir, "2", "pending", None);
+        edit_task(&team_dir, "2", |t| t.blocked_by = vec!["1".to_string()]);
+        let service = task_scoped_service(tmp.path());
+
+        let held = hold(&team_dir.join(".locks/1.lock"));
+        let err = service.do_claim(claim_params("2")).unwrap_err();
+        assert_eq!(ToolError::from_anyhow(&err).code, ErrorCode::LockBusy);
+
+        drop(held);
+        service.do_claim(claim_params("2")).unwrap();
+    }
+
+    #[test]
+    fn per_task_ops_respect_exclusive_team_lock() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("team");
+        setup_team(&team_dir, "1", "pending", None);
+        let service = task_scoped_service(tmp.path());
+
+        let _held = hold(&team_dir.join(".lock"));
+        let err = service.do_claim(claim_params("1")).unwrap_err();
+        assert_eq!(ToolError::from_anyhow(&err).code, ErrorCode::LockBusy);
+    }
+
+    /// Compares claim throughput of team-wide and per-task locking with
+    /// several agents claiming disjoint tasks at once. Run with
+    /// `cargo test --release -- --ignored --nocapture claim_throughput`.
+    #[test]
+    #[ignore = "benchmark"]
+    fn claim_throughput() {
+        const AGENTS: usize = 8;
+        const TASKS: usize = 256;
+
+        for scope in [LockScope::Team, LockScope::Task] {
+            let tmp = tempfile::tempdir().unwrap();
+            let team_dir = tmp.path().join("team");
+            for id in 0..TASKS {
+                setup_team(&team_dir, &id.to_string(), "pending", None);
+            }
+            let config = Config {
+                lock_scope: Some(scope),
+                ..Default::default()
+            };
+            let service = SafeTaskClaim::new(tmp.path().to_path_buf(), config);
+
+            let started = std::time::Instant::now();
+            std::thread::scope(|threads| {
+                for agent in 0..AGENTS {
+                    let service = &service;
+                    threads.spawn(move || {
+                        for id in (agent..TASKS).step_by(AGENTS) {
+                            let params = SafeClaimParams {
+                                owner: format!("agent-{agent}"),
+                                target: TeamArgs::default(),
+                                ..claim_params(&id.to_string())
+                            };
+                            service.do_claim(params).unwrap();
+                        }
+                    });
+                }
+            });
+            let elapsed = started.elapsed();
+            let rate = TASKS as f64 / elapsed.as_secs_f64();
+            println!("{scope:?}: {TASKS} claims by {AGENTS} agents in {elapsed:?} ({rate:.0}/s)");
+        }
+    }
+
+    fn list_params() -> ListTasksParams {
+        ListTasksParams {
+            target: TeamArgs::default(),
+            status: None,
+            owner: None,
+            available_only: false,
+        }
+    }
+
+    fn listed_ids(list: &TaskList) -> Vec<&str> {
+        list.tasks.iter().map(|t| t.id.as_str()).collect()
+    }
+
+    #[test]
+    fn list_tasks_filters_by_status_owner_and_availability() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("team");
+        setup_team(&team_dir, "10", "pending", None);
+        setup_team(&team_dir, "2", "in_progress", Some("agent-a"));
+        setup_team(&team_dir, "3", "completed", None);
+        setup_team(&team_dir, "4", "pending", None);
+        setup_team(&team_dir, "5", "pending", Some("agent-b"));
+        edit_task(&team_dir, "4", |t| t.blocked_by = vec!["2".to_string()]);
+        edit_task(&team_dir, "10", |t| t.blocked_by = vec!["3".to_string()]);
+        let service = test_service(tmp.path());
+
+        let all = service.do_list(list_params()).unwrap();
+        assert_eq!(listed_ids(&all), ["2", "3", "4", "5", "10"]);
+
+        let pending = service
+            .do_list(ListTasksParams {
+                status: Some("pending".to_string()),
+                ..list_params()
+            })
+            .unwrap();
+        assert_eq!(listed_ids(&pending), ["4", "5", "10"]);
+
+        let mine = service
+            .do_list(ListTasksParams {
+                owner: Some("agent-a".to_string()),
+                ..list_params()
+            })
+            .unwrap();
+        assert_eq!(listed_ids(&mine), ["2"]);
+
+        let available = service
+            .do_list(ListTasksParams {
+                available_only: true,
+                ..list_params()
+            })
+            .unwrap();
+        assert_eq!(listed_ids(&available), ["10"]);
+    }
+
+    #[test]
+    fn list_tasks_runs_alongside_other_readers() {
+        let tmp = tempfile::tempdir().unwrap();
+        setup_team(&tmp.path().join("team"), "1", "pending", None);
+        let service = test_service(tmp.path());
+
+        let list = service
+            .with_team_read_locked(None, None, |_| {
+                service.do_list(ListTasksParams {
+                    target: TeamArgs {
+                        team: None,
+                        wait: Some(false),
+                    },
+                    ..list_params()
+                })
+            })
+            .unwrap();
+        assert_eq!(list.tasks.len(), 1);
+
+        let output = list.structured().unwrap();
+        assert_eq!(output["tasks"][0]["available"], true);
+        assert!(output["tasks"][0].get("owner").is_none());
+        assert!(list.message().contains("1\tpending\t-\tTest task"));
+    }
+
+    fn get_params(task_id: &str) -> GetTaskParams {
+        GetTaskParams {
+            task_id: task_id.to_string(),
+            target: TeamArgs {
+                team: None,
+                wait: Some(false),
+            },
+        }
+    }
+
+    #[test]
+    fn get_task_returns_snapshot_with_revision() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("team");
+        setup_team(&team_dir, "1", "pending", None);
+        let service = test_service(tmp.path());
+
+        let first = service.do_get(get_params("1")).unwrap();
+        assert_eq!(first.task.status, "pending");
+        assert_eq!(first.revision.len(), 16);
+        assert_eq!(service.do_get(get_params("1")).unwrap().revision, first.revision);
+
+        service.do_claim(claim_params("1")).unwrap();
+        let second = service.do_get(get_params("1")).unwrap();
+        assert_eq!(second.task.owner.as_deref(), Some("agent-a"));
+        assert_ne!(second.revision, first.revision);
+
+        let output = second.structured().unwrap();
+        assert_eq!(output["task"]["status"], "in_progress");
+        assert_eq!(output["revision"], second.revision);
+    }
+
+    #[test]
+    fn get_task_waits_for_writers() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("team");
+        setup_team(&team_dir, "1", "pending", None);
+        let service = test_service(tmp.path());
+
+        let err = service
+            .with_team_locked(None, None, |_| service.do_get(get_params("1")))
+            .unwrap_err();
+        assert_eq!(ToolError::from_anyhow(&err).code, ErrorCode::LockBusy);
+
+        let err = service.do_get(get_params("../1")).unwrap_err();
+        assert_eq!(ToolError::from_anyhow(&err).code, ErrorCode::InvalidArgument);
+        let err = service.do_get(get_params("2")).unwrap_err();
+        assert_eq!(ToolError::from_anyhow(&err).code, ErrorCode::NotFound);
+    }
+
+    #[test]
+    fn revision_is_stable_fnv1a() {
+        assert_eq!(revision(b""), "cbf29ce484222325");
+        assert_eq!(revision(b"a"), "af63dc4c8601ec8c");
+    }
+
+    fn update_params(revision: &str, patch: serde_json::Value) -> SafeUpdateParams {
+        SafeUpdateParams {
+            task_id: "1".to_string(),
+            expected_revision: revision.to_string(),
+            patch,
+            target: TeamArgs {
+                team: None,
+                wait: Some(false),
+            },
+        }
+    }
+
+    #[test]
+    fn safe_update_applies_merge_patch_at_expected_revision() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("team");
+        setup_team(&team_dir, "1", "pending", None);
+        edit_task(&team_dir, "1", |t| {
+            t.metadata = Some(serde_json::json!({ "keep": 1, "drop": 2 }));
+            t.extra.insert("customField".to_string(), "x".into());
+        });
+        let service = test_service(tmp.path());
+        let before = service.do_get(get_params("1")).unwrap();
+
+        let patch = serde_json::json!({
+            "description": "Updated",
+            "metadata": { "drop": null, "added": true },
+        });
+        let updated = service.do_update(update_params(&before.revision, patch)).unwrap();
+        assert_ne!(updated.revision, before.revision);
+        assert_eq!(service.do_get(get_params("1")).unwrap().revision, updated.revision);
+
+        let task = load_task(&team_dir, "1");
+        assert_eq!(task.description, "Updated");
+        assert_eq!(task.metadata, Some(serde_json::json!({ "keep": 1, "added": true })));
+        assert_eq!(task.extra["customField"], "x");
+    }
+
+    #[test]
+    fn safe_update_with_stale_revision_conflicts() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("team");
+        setup_team(&team_dir, "1", "pending", None);
+        let service = test_service(tmp.path());
+        let stale = service.do_get(get_params("1")).unwrap().revision;
+        service.do_claim(claim_params("1")).unwrap();
+        let current = fs::read(team_dir.join("1.json")).unwrap();
+
+        let patch = serde_json::json!({ "description": "Lost update" });
+        let err = service.do_update(update_params(&stale, patch)).unwrap_err();
+        let error = ToolError::from_anyhow(&err);
+        assert_eq!(error.code, ErrorCode::Conflict);
+        assert_eq!(error.owner.as_deref(), Some("agent-a"));
+        let details = error.details.unwrap();
+        assert_eq!(details["revision"], revision(&current));
+        assert_eq!(details["task"]["status"], "in_progress");
+        assert_eq!(fs::read(team_dir.join("1.json")).unwrap(), current);
+    }
+
+    #[test]
+    fn safe_update_rejects_invalid_patches() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("team");
+        setup_team(&team_dir, "1", "pending", None);
+        let service = test_service(tmp.path());
+        let rev = service.do_get(get_params("1")).unwrap().revision;
+
+        for patch in [
+            serde_json::json!("not an object"),
+            serde_json::json!({ "status": "completed" }),
+            serde_json::json!({ "owner": "agent-b" }),
+            serde_json::json!({ "id": "2" }),
+            serde_json::json!({ "subject": null }),
+            serde_json::json!({ "blockedBy": "1" }),
+            serde_json::json!({ "blockedBy": ["2"] }),
+            serde_json::json!({ "blocks": [] }),
+        ] {
+            let err = service.do_update(update_params(&rev, patch.clone())).unwrap_err();
+            let code = ToolError::from_anyhow(&err).code;
+            assert_eq!(code, ErrorCode::InvalidArgument, "{patch}");
+        }
+        assert_eq!(service.do_get(get_params("1")).unwrap().revision, rev);
+    }
+
+    #[test]
+    fn safe_update_rejects_lease_changes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("team");
+        setup_team(&team_dir, "1", "pending", None);
+        let service = test_service(tmp.path());
+        service.do_claim(claim_params("1")).unwrap();
+        let rev = service.do_get(get_params("1")).unwrap().revision;
+
+        for patch in [
+            serde_json::json!({ "metadata": { "leaseExpiresAt": 0 } }),
+            serde_json::json!({ "metadata": { "claimedAt": null } }),
+            serde_json::json!({ "metadata": null }),
+            serde_json::json!({ "metadata": "none" }),
+        ] {
+            let err = service.do_update(update_params(&rev, patch.clone())).unwrap_err();
+            let code = ToolError::from_anyhow(&err).code;
+            assert_eq!(code, ErrorCode::InvalidArgument, "{patch}");
+        }
+        assert_eq!(service.do_get(get_params("1")).unwrap().revision, rev);
+
+        let patch = serde_json::json!({ "metadata": { "notes": "halfway" } });
+        let updated = service.do_update(update_params(&rev, patch)).unwrap();
+        assert!(updated.task.lease_expires_at().is_some());
+    }
+
+    #[test]
+    fn task_scoped_update_runs_alongside_readers() {
+        let tmp = tempfile::tempdir().unwrap();
+        setup_team(&tmp.path().join("team"), "1", "pending", None);
+        let service = task_scoped_service(tmp.path());
+        let rev = service.do_get(get_params("1")).unwrap().revision;
+
+        let updated = service
+            .with_team_read_locked(None, None, |_| {
+                let patch = serde_json::json!({ "description": "Edited" });
+                service.do_update(update_params(&rev, patch))
+            })
+            .unwrap();
+        assert_eq!(updated.task.description, "Edited");
+    }
+
+    #[test]
+    fn merge_patch_follows_rfc_7396() {
+        let mut target = serde_json::json!({ "a": "b", "c": { "d": "e", "f": "g" } });
+        merge_patch(&mut target, &serde_json::json!({ "a": "z", "c": { "f": null } }));
+        assert_eq!(target, serde_json::json!({ "a": "z", "c": { "d": "e" } }));
+
+        let mut target = serde_json::json!({ "a": [1] });
+        merge_patch(&mut target, &serde_json::json!({ "a": { "b": 1 }, "n": [null] }));
+        assert_eq!(target, serde_json::json!({ "a": { "b": 1 }, "n": [null] }));
+    }
+
+    fn create_params(subject: &str, blocked_by: &[&str]) -> CreateTaskParams {
+        CreateTaskParams {
+            subject: subject.to_string(),
+            description: format!("Do {subject}"),
+            active_form: format!("Doing {subject}"),
+            blocked_by: blocked_by.iter().map(|id| id.to_string()).collect(),
+            metadata: None,
+            target: TeamArgs::default(),
+        }
+    }
+
+    #[test]
+    fn create_task_allocates_past_high_water_mark() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("team");
+        setup_team(&team_dir, "3", "pending", None);
+        let service = test_service(tmp.path());
+
+        let created = service.do_create(create_params("first", &[])).unwrap();
+        assert_eq!(created.task.id, "4");
+        assert_eq!(load_task(&team_dir, "4").status, "pending");
+
+        fs::remove_file(team_dir.join("4.json")).unwrap();
+        let created = service.do_create(create_params("second", &[])).unwrap();
+        assert_eq!(created.task.id, "5");
+
+        fs::write(team_dir.join("9.json"), "written without the lock").unwrap();
+        let created = service.do_create(create_params("third", &[])).unwrap();
+        assert_eq!(created.task.id, "10");
+        assert_eq!(fs::read_to_string(team_dir.join(".highwatermark")).unwrap(), "10");
+    }
+
+    #[test]
+    fn create_task_refuses_bad_high_water_mark_and_exhausted_ids() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("team");
+        setup_team(&team_dir, "3", "pending", None);
+        let service = test_service(tmp.path());
+
+        fs::write(team_dir.join(".highwatermark"), "garbage").unwrap();
+        let err = service.do_create(create_params("first", &[])).unwrap_err();
+        assert!(err.to_string().contains("not a task ID"), "{err:#}");
+
+        fs::remove_file(team_dir.join(".highwatermark")).unwrap();
+        fs::write(team_dir.join(format!("{}.json", u64::MAX)), "stray").unwrap();
+        let err = service.do_create(create_params("second", &[])).unwrap_err();
+        assert_eq!(ToolError::from_anyhow(&err).code, ErrorCode::Internal);
+        assert_eq!(fs::read_dir(&team_dir).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn create_task_updates_reciprocal_blocks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("team");
+        setup_team(&team_dir, "1", "pending", None);
+        setup_team(&team_dir, "2", "completed", None);
+        let service = test_service(tmp.path());
+
+        let created = service.do_create(create_params("child", &["1", "2", "1"])).unwrap();
+        assert_eq!(created.task.blocked_by, ["1", "2"]);
+        assert_eq!(load_task(&team_dir, "1").blocks, ["3"]);
+        assert_eq!(load_task(&team_dir, "2").blocks, ["3"]);
+        assert_eq!(load_task(&team_dir, "3").blocked_by, ["1", "2"]);
+    }
+
+    #[test]
+    fn create_task_with_missing_blocker_creates_nothing() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("team");
+        setup_team(&team_dir, "1", "pending", None);
+        let service = test_service(tmp.path());
+
+        let err = service.do_create(create_params("child", &["1", "7"])).unwrap_err();
+        assert_eq!(ToolError::from_anyhow(&err).code, ErrorCode::NotFound);
+        assert!(!team_dir.join("2.json").exists());
+        assert!(load_task(&team_dir, "1").blocks.is_empty());
+
+        let err = service.do_create(create_params("child", &["../1"])).unwrap_err();
+        assert_eq!(ToolError::from_anyhow(&err).code, ErrorCode::InvalidArgument);
+    }
+
+    #[test]
+    fn concurrent_creates_get_distinct_ids() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::create_dir_all(tmp.path().join("team")).unwrap();
+        let service = test_service(tmp.path());
+
+        let ids: HashSet<String> = std::thread::scope(|threads| {
+            let handles: Vec<_> = (0..8)
+                .map(|agent| {
+                    let service = &service;
+                    threads.spawn(move || {
+                        (0..5)
+                            .map(|n| {
+                                let params = create_params(&format!("{agent}-{n}"), &[]);
+                                service.do_create(params).unwrap().task.id
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            handles.into_iter().flat_map(|h| h.join().unwrap()).collect()
+        });
+        assert_eq!(ids.len(), 40);
+        assert!((1..=40).all(|id| ids.contains(&id.to_string())));
+    }
+
+    fn dependency(task_id: &str, blocker_id: &str) -> DependencyParams {
+        DependencyParams {
+            task_id: task_id.to_string(),
+            blocker_id: blocker_id.to_string(),
+            target: TeamArgs::default(),
+        }
+    }
+
+    #[test]
+    fn add_and_remove_dependency_update_both_tasks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("team");
+        setup_team(&team_dir, "1", "pending", None);
+        setup_team(&team_dir, "2", "pending", None);
+        let service = test_service(tmp.path());
+
+        let outcome = service.do_add_dependency(dependency("2", "1")).unwrap();
+        assert_eq!(outcome.task.blocked_by, ["1"]);
+        assert_eq!(load_task(&team_dir, "2").blocked_by, ["1"]);
+        assert_eq!(load_task(&team_dir, "1").blocks, ["2"]);
+
+        service.do_add_dependency(dependency("2", "1")).unwrap();
+        assert_eq!(load_task(&team_dir, "1").blocks, ["2"]);
+
+        service.do_remove_dependency(dependency("2", "1")).unwrap();
+        assert!(load_task(&team_dir, "2").blocked_by.is_empty());
+        assert!(load_task(&team_dir, "1").blocks.is_empty());
+    }
+
+    #[test]
+    fn add_dependency_repairs_one_sided_edge() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("team");
+        setup_team(&team_dir, "1", "pending", None);
+        setup_team(&team_dir, "2", "pending", None);
+        edit_task(&team_dir, "1", |t| t.blocks = vec!["2".to_string()]);
+        let service = test_service(tmp.path());
+
+        service.do_add_dependency(dependency("2", "1")).unwrap();
+        assert_eq!(load_task(&team_dir, "2").blocked_by, ["1"]);
+        assert_eq!(load_task(&team_dir, "1").blocks, ["2"]);
+    }
+
+    #[test]
+    fn add_dependency_rejects_cycles_with_path() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("team");
+        for id in ["1", "2", "3"] {
+            setup_team(&team_dir, id, "pending", None);
+        }
+        let service = test_service(tmp.path());
+        service.do_add_dependency(dependency("2", "1")).unwrap();
+        service.do_add_dependency(dependency("3", "2")).unwrap();
+
+        let err = service.do_add_dependency(dependency("1", "3")).unwrap_err();
+        let error = ToolError::from_anyhow(&err);
+        assert_eq!(error.code, ErrorCode::DependencyCycle);
+        assert_eq!(error.details.unwrap()["cycle"], serde_json::json!(["1", "3", "2", "1"]));
+        assert!(error.message.contains("1 -> 3 -> 2 -> 1"));
+        assert!(load_task(&team_dir, "1").blocked_by.is_empty());
+        assert!(load_task(&team_dir, "3").blocks.is_empty());
+
+        let err = service.do_add_dependency(dependency("1", "1")).unwrap_err();
+        let error = ToolError::from_anyhow(&err);
+        assert_eq!(error.details.unwrap()["cycle"], serde_json::json!(["1", "1"]));
+    }
+
+    #[test]
+    fn remove_dependency_tolerates_missing_blocker() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("team");
+        setup_team(&team_dir, "2", "pending", None);
+        edit_task(&team_dir, "2", |t| t.blocked_by = vec!["1".to_string()]);
+        let service = test_service(tmp.path());
+
+        let err = service.do_add_dependency(dependency("2", "9")).unwrap_err();
+        assert_eq!(ToolError::from_anyhow(&err).code, ErrorCode::NotFound);
+
+        service.do_remove_dependency(dependency("2", "1")).unwrap();
+        assert!(load_task(&team_dir, "2").blocked_by.is_empty());
+    }
+
+    #[test]
+    fn check_team_repair_writes_fixes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("team");
+        setup_team(&team_dir, "1", "pending", Some("agent-a"));
+        setup_team(&team_dir, "2", "pending", None);
+        edit_task(&team_dir, "2", |t| t.blocked_by = vec!["1".to_string(), "9".to_string()]);
+        let service = test_service(tmp.path());
+        let params = |repair| CheckTeamParams {
+            target: TeamArgs {
+                team: None,
+                wait: Some(false),
+            },
+            repair,
+        };
+
+        let report = service.do_check(params(false)).unwrap();
+        assert_eq!(report.team, "team");
+        assert_eq!(report.issues.len(), 3);
+        assert!(report.has_errors());
+        assert_eq!(load_task(&team_dir, "1").owner.as_deref(), Some("agent-a"));
+
+        let report = service.do_check(params(true)).unwrap();
+        assert!(report.issues.iter().all(|issue| issue.repaired));
+        assert_eq!(load_task(&team_dir, "1").owner, None);
+        assert_eq!(load_task(&team_dir, "1").blocks, ["2"]);
+        assert_eq!(load_task(&team_dir, "2").blocked_by, ["1"]);
+        assert!(service.do_check(params(false)).unwrap().issues.is_empty());
+
+        let err = service
+            .with_team_read_locked(None, None, |_| service.do_check(params(true)))
+            .unwrap_err();
+        assert_eq!(ToolError::from_anyhow(&err).code, ErrorCode::LockBusy);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn cancelled_claim_leaves_task_untouched() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("busy");
+        setup_team(&team_dir, "1", "pending", None);
+        let lock_path = team_dir.join(".lock");
+        let holder =
+            TeamLock::acquire(&lock_path, LockMode::Exclusive, &LockOptions::default()).unwrap();
+
+        let service = test_service(tmp.path());
+        let params = SafeClaimParams {
+            task_id: "1".to_string(),
+            owner: "agent-a".to_string(),
+            target: TeamArgs {
+                team: Some("busy".to_string()),
+                wait: None,
+            },
+            force: false,
+            lease_secs: None,
+        };
+        let ct = CancellationToken::new();
+        let waiting = tokio::spawn({
+            let service = service.clone();
+            let ct = ct.clone();
+            async move { service.safe_claim(Parameters(params), ct).await }
+        });
+        tokio::task::yield_now().await;
+
+        ct.cancel();
+        let result = waiting.await.unwrap().unwrap();
+        drop(holder);
+
+        assert_eq!(result.is_error, Some(true));
+        assert_eq!(error_json(&result)["code"], "CANCELLED");
+        let task = load_task(&team_dir, "1");
+        assert_eq!(task.status, "pending");
+        assert_eq!(task.owner, None);
+    }
+
+    #[test]
+    fn cancelled_request_does_not_write() {
+        let tmp = tempfile::tempdir().unwrap();
+        let team_dir = tmp.path().join("test-team");
+        setup_team(&team_dir, "1", "pending", None);
+
+        let service = test_service(tmp.path());
+        service.cancel.cancel();
+        let err = service
+            .claim_under_lock(&team_dir.join("1.json"), "1", "agent-a", false, 60)
+            .unwrap_err();
+        assert_eq!(ToolError::from_anyhow(&err).code, ErrorCode::Cancelled);
+        assert_eq!(load_task(&team_dir, "1").status, "pending");
     }
 }